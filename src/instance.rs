@@ -3,4 +3,36 @@ pub mod client;
 /// サーバー関連の機能を提供するモジュール。
 pub mod server;
 /// イベント処理関連の機能を提供するモジュール。
-pub mod event;
\ No newline at end of file
+pub mod event;
+/// 単一インスタンス制御の機能を提供するモジュール。
+pub mod single;
+/// インスタンス名の導出機能を提供するモジュール。
+pub mod name;
+/// メッセージ型を検証するクライアントを提供するモジュール。
+pub mod typed;
+/// 相関IDによるリクエスト/レスポンスの機能を提供するモジュール。
+pub mod rpc;
+/// 型付きのサービス定義を提供するモジュール。
+pub mod service;
+/// トピックによる出版/購読の機能を提供するモジュール。
+pub mod pubsub;
+/// サーバーのイベントループとハンドラーを提供するモジュール。
+pub mod handler;
+/// 切断時に自動的に再接続するクライアントを提供するモジュール。
+pub mod reconnect;
+/// クライアントを受信側と送信側に分割する機能を提供するモジュール。
+pub mod split;
+/// OSのレディネス通知による待機の機能を提供するモジュール。
+pub(crate) mod readiness;
+/// 接続の相手の資格情報を検証する機能を提供するモジュール。
+#[cfg(unix)]
+pub(crate) mod credentials;
+/// tokioで使用する非同期クライアントを提供するモジュール。
+#[cfg(feature = "async")]
+pub mod async_client;
+/// tokioで使用する非同期サーバーを提供するモジュール。
+#[cfg(feature = "async")]
+pub mod async_server;
+/// 非同期クライアントを`Stream`/`Sink`として扱うアダプターを提供するモジュール。
+#[cfg(feature = "async")]
+pub mod async_stream;
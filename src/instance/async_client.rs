@@ -0,0 +1,293 @@
+#[cfg(unix)]
+use crate::instance::credentials;
+use crate::instance::event::DisconnectReason;
+use crate::instance::name;
+use crate::instance::server::ConnectionId;
+use crate::protocol::codec::Codec;
+use crate::protocol::{
+    self, Capabilities, Frame, FrameDecoder, FrameKind, Hello, ProtocolConfig, ProtocolError, ProtocolErrorKind,
+    HELLO_LEN,
+};
+use interprocess::local_socket::tokio::prelude::*;
+use interprocess::local_socket::tokio::{RecvHalf, SendHalf};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, Result};
+use std::sync::{Arc, MutexGuard};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+/// 1回の読み込みで受信を試みる最大バイト数。
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// tokioのランタイム上でサーバーと通信する非同期クライアント。
+///
+/// [`Client`](crate::Client)と同じハンドシェイクとフレーム形式を使用するため、
+/// 同期版のサーバーやクライアントとそのまま通信できます。
+///
+/// クローンしたクライアントは同じ接続を共有します。送信と受信はそれぞれ別のロックで保護されるため、
+/// 別々のタスクから同時に送受信できます。
+///
+/// 非同期版はメッセージの送受信のみに対応します。ハンドシェイクでは追加機能に対応していないことを通知するため、
+/// 相手がRPCや出版/購読を使用することはありません。
+#[derive(Clone)]
+pub struct AsyncClient {
+    shared: Arc<Shared>,
+    config: ProtocolConfig,
+    negotiated: Hello,
+    connection_id: Option<ConnectionId>,
+}
+
+/// クローン間で共有される接続の状態。
+struct Shared {
+    reader: Mutex<Reader>,
+    writer: Mutex<SendHalf>,
+    /// 接続が切断された理由。切断されていなければNone。
+    disconnected: std::sync::Mutex<Option<DisconnectReason>>,
+}
+
+/// 受信側の状態。
+struct Reader {
+    half: RecvHalf,
+    decoder: FrameDecoder,
+}
+
+impl AsyncClient {
+    /// 指定された名前のサーバーに接続を開始します。
+    ///
+    /// 接続を確立し、サーバーとハンドシェイクを行ってプロトコルバージョンを決定します。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    ///
+    /// # エラー
+    /// 接続に失敗した場合や、サポートされていないソケットタイプの場合、
+    /// サーバーとプロトコルバージョンの互換性がない場合にエラーを返します。
+    pub async fn start(name: &str) -> Result<Self> {
+        Self::start_with(name, ProtocolConfig::default()).await
+    }
+
+    /// 指定されたプロトコル設定でサーバーに接続を開始します。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    /// - `config`: この接続に適用するプロトコルの設定。
+    ///
+    /// # エラー
+    /// 接続に失敗した場合や、サポートされていないソケットタイプの場合、
+    /// サーバーとプロトコルバージョンの互換性がない場合にエラーを返します。
+    pub async fn start_with(name: &str, config: ProtocolConfig) -> Result<Self> {
+        let stream = LocalSocketStream::connect(name::resolve(name)?).await?;
+        Self::establish(stream, config).await
+    }
+
+    /// ストリームを送信側と受信側に分割し、相手とハンドシェイクを行ってクライアントを生成します。
+    ///
+    /// 同期版と同様に、設定で要求されている場合は相手が同じユーザーであることを確認し、
+    /// 先に自身のハンドシェイクメッセージを送信してから相手のメッセージを読みます。
+    /// ハンドシェイクを行わないNDJSONモードでは、そのままクライアントを生成します。
+    pub(crate) async fn establish(stream: LocalSocketStream, config: ProtocolConfig) -> Result<Self> {
+        let (mut reader, mut writer) = stream.split();
+        if config.get_require_same_user() {
+            verify_peer(&reader)?;
+        }
+        let local = Hello {
+            capabilities: Capabilities::NONE,
+            ..Hello::local()
+        };
+        let negotiated = if config.uses_handshake() {
+            writer.write_all(&local.encode()).await?;
+            let mut bytes = [0u8; HELLO_LEN];
+            tokio::time::timeout(config.get_handshake_timeout(), reader.read_exact(&mut bytes))
+                .await
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "Peer did not complete the handshake in time")
+                })??;
+            local.negotiate(&Hello::decode(&bytes)?)?
+        } else {
+            local
+        };
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                reader: Mutex::new(Reader {
+                    half: reader,
+                    decoder: FrameDecoder::with_config(config.clone()),
+                }),
+                writer: Mutex::new(writer),
+                disconnected: std::sync::Mutex::new(None),
+            }),
+            config,
+            negotiated,
+            connection_id: None,
+        })
+    }
+
+    /// サーバーにメッセージを送信します。
+    ///
+    /// 他のクローンが同時に送信していても、フレームの途中に別のフレームが挟まることはありません。
+    /// 送信の途中で`Future`を破棄するとフレームが途切れ、以降の通信は失敗します。
+    ///
+    /// # 引数
+    /// - `message`: 送信するメッセージ。`Serialize`トレイトを実装している必要があります。
+    ///
+    /// # エラー
+    /// メッセージのシリアライズまたは送信に失敗した場合や、フレームが最大サイズを超える場合にエラーを返します。
+    pub async fn send<T: Serialize>(&self, message: &T) -> Result<()> {
+        let frame = Frame::data(self.config.get_codec().encode(message)?);
+        self.write_bytes(&protocol::encode_frame(&frame, &self.config)?).await
+    }
+
+    /// サーバーからメッセージを受信します。
+    ///
+    /// メッセージが届くまで待ちます。受信の途中で`Future`を破棄しても、読み込んだバイト列は失われず、
+    /// 次回の受信で続きから組み立てられます。そのため、`tokio::select!`や`tokio::time::timeout`と組み合わせて使用できます。
+    ///
+    /// # エラー
+    /// 接続が閉じられた場合や、メッセージのデシリアライズに失敗した場合、
+    /// フレームが最大サイズを超える場合にエラーを返します。
+    pub async fn recv<T: DeserializeOwned>(&self) -> Result<T> {
+        let frame = self.next_frame().await?;
+        self.config.get_codec().decode(&frame.payload)
+    }
+
+    /// この接続に適用されているプロトコルの設定を取得します。
+    pub fn get_config(&self) -> &ProtocolConfig {
+        &self.config
+    }
+
+    /// ハンドシェイクで決定したプロトコルバージョンを取得します。
+    pub fn get_protocol_version(&self) -> u16 {
+        self.negotiated.version
+    }
+
+    /// ハンドシェイクで決定した、両端が対応している機能を取得します。
+    ///
+    /// 非同期版は追加機能に対応していないため、常に[`Capabilities::NONE`]です。
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.negotiated.capabilities
+    }
+
+    /// サーバーが受け入れた接続に割り当てた接続IDを取得します。
+    ///
+    /// [`AsyncServer`](crate::AsyncServer)が受け入れた接続以外ではNoneを返します。
+    pub fn get_connection_id(&self) -> Option<ConnectionId> {
+        self.connection_id
+    }
+
+    /// 接続が切断されたことを検出済みであれば、その理由を返します。
+    pub fn get_disconnect_reason(&self) -> Option<DisconnectReason> {
+        *self.lock_disconnected()
+    }
+
+    /// 接続IDを設定します。
+    pub(crate) fn set_connection_id(&mut self, id: ConnectionId) {
+        self.connection_id = Some(id);
+    }
+
+    /// エンコード済みのフレームを途切れずにすべて書き込みます。
+    ///
+    /// 切断を示すエラーで失敗した場合は、その理由を記録します。
+    pub(crate) async fn write_bytes(&self, bytes: &[u8]) -> Result<()> {
+        let written = self.shared.writer.lock().await.write_all(bytes).await;
+        if let Err(e) = &written
+            && let Some(reason) = DisconnectReason::from_error(e)
+        {
+            self.lock_disconnected().get_or_insert(reason);
+        }
+        written
+    }
+
+    /// 送信側を閉じ、これ以上送信しないことを相手に通知します。
+    ///
+    /// 受信側は閉じないため、相手が送信済みのメッセージは引き続き受信できます。
+    pub(crate) async fn shutdown_writer(&self) -> Result<()> {
+        let mut writer = self.shared.writer.lock().await;
+        writer.flush().await?;
+        shutdown_send(&writer)
+    }
+
+    /// メッセージのフレームを1つ受信します。
+    ///
+    /// 最大サイズを超えるフレームによりデコーダーが受信を停止した場合は、送信側を閉じて相手に通知します。
+    async fn next_frame(&self) -> Result<Frame> {
+        let mut reader = self.shared.reader.lock().await;
+        loop {
+            let frame = reader.decoder.next_frame();
+            if reader.decoder.is_closed() {
+                // 既に切断済みの場合のエラーは無視する
+                let _ = self.shutdown_writer().await;
+            }
+            match frame? {
+                Some(frame) if frame.kind == FrameKind::Data => return Ok(frame),
+                Some(frame) => {
+                    return Err(ProtocolError::new(
+                        ProtocolErrorKind::InvalidFrame,
+                        format!("Unexpected {:?} frame on a connection without extensions", frame.kind),
+                    )
+                    .into());
+                }
+                None => {}
+            }
+            if let Some(reason) = self.get_disconnect_reason() {
+                return Err(reason.into());
+            }
+
+            let mut buf = [0u8; READ_CHUNK_SIZE];
+            match reader.half.read(&mut buf).await {
+                Ok(0) => *self.lock_disconnected() = Some(DisconnectReason::Closed),
+                Ok(n) => reader.decoder.extend(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    if let Some(reason) = DisconnectReason::from_error(&e) {
+                        *self.lock_disconnected() = Some(reason);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// 切断の理由をロックします。
+    fn lock_disconnected(&self) -> MutexGuard<'_, Option<DisconnectReason>> {
+        self.shared.disconnected.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// 相手が自身と同じユーザーのプロセスであることを確認します（Unix用）。
+#[cfg(unix)]
+fn verify_peer(reader: &RecvHalf) -> Result<()> {
+    use std::os::fd::AsFd;
+
+    let RecvHalf::UdSocket(half) = reader;
+    credentials::verify_same_user(half.as_fd())
+}
+
+/// ソケットの送信方向を閉じ、相手にEOFを通知します（Unix用）。
+///
+/// interprocessの`shutdown`は何もしないため、ソケットに対して直接shutdownを呼び出します。
+#[cfg(unix)]
+fn shutdown_send(writer: &SendHalf) -> Result<()> {
+    use std::os::fd::{AsFd, AsRawFd};
+
+    let SendHalf::UdSocket(half) = writer;
+    // SAFETY: 有効なファイルディスクリプタに対するshutdownはメモリ安全性に影響しません。
+    if unsafe { libc::shutdown(half.as_fd().as_raw_fd(), libc::SHUT_WR) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 名前付きパイプは送信方向だけを閉じられないため、何もしません（非Unix用）。
+#[cfg(not(unix))]
+fn shutdown_send(_writer: &SendHalf) -> Result<()> {
+    Ok(())
+}
+
+/// 相手の資格情報を取得できないため、検証できないものとしてエラーを返します（非Unix用）。
+#[cfg(not(unix))]
+fn verify_peer(_reader: &RecvHalf) -> Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Peer credentials are not available on this platform",
+    ))
+}
@@ -0,0 +1,200 @@
+use crate::instance::async_client::AsyncClient;
+use crate::instance::name;
+use crate::instance::server::ConnectionId;
+use crate::protocol::ProtocolConfig;
+use interprocess::local_socket::tokio::prelude::*;
+use std::future::Future;
+use std::io::Result;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// 実行中の非同期処理。
+type Pending<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+/// tokioのランタイム上でクライアントからの接続を待ち受ける非同期サーバー。
+///
+/// [`Server`](crate::Server)と同じハンドシェイクとフレーム形式を使用するため、
+/// 同期版のクライアントからの接続もそのまま受け入れられます。
+pub struct AsyncServer {
+    listener: Arc<name::Listening<LocalSocketListener>>,
+    config: ProtocolConfig,
+    /// 実行中の接続の受け入れ。
+    accepting: Option<Pending<LocalSocketStream>>,
+    /// 受け入れ済みで、ハンドシェイクが完了していない接続。
+    handshakes: Vec<Pending<AsyncClient>>,
+    next_id: u64,
+}
+
+impl AsyncServer {
+    /// 新しいサーバーインスタンスを作成し、接続の待ち受けを開始します。
+    ///
+    /// tokioのランタイム内から呼び出す必要があります。
+    ///
+    /// # 引数
+    /// - `name`: パイプまたはソケットの名前。
+    ///
+    /// # エラー
+    /// パイプ/ソケットの作成に失敗した場合や、サポートされていないソケットタイプの場合にエラーを返します。
+    pub fn start(name: &str) -> Result<Self> {
+        Self::start_with(name, ProtocolConfig::default())
+    }
+
+    /// 指定されたプロトコル設定でサーバーを作成し、接続の待ち受けを開始します。
+    ///
+    /// tokioのランタイム内から呼び出す必要があります。
+    /// 設定は、このサーバーが受け入れたすべての接続に適用されます。
+    /// 同期版と同様に、クラッシュしたサーバーが残したソケットファイルは削除してから待ち受けを開始します。
+    ///
+    /// # 引数
+    /// - `name`: パイプまたはソケットの名前。
+    /// - `config`: 受け入れた接続に適用するプロトコルの設定。
+    ///
+    /// # エラー
+    /// パイプ/ソケットの作成に失敗した場合や、サポートされていないソケットタイプの場合にエラーを返します。
+    pub fn start_with(name: &str, config: ProtocolConfig) -> Result<Self> {
+        let listener = name::listen(name, true, |opts| opts.create_tokio())?;
+        Ok(Self {
+            listener: Arc::new(listener),
+            config,
+            accepting: None,
+            handshakes: Vec::new(),
+            next_id: 1,
+        })
+    }
+
+    /// クライアントからの接続を待って受け入れ、ハンドシェイクを行います。
+    ///
+    /// ハンドシェイクは受け入れた接続ごとに並行して進められ、最初に完了した接続を返します。
+    /// 応答しない相手がいても、他の接続の受け入れやハンドシェイクは妨げられません。
+    /// 受け入れた接続には、同期版と同様にサーバーごとの接続IDが割り当てられます。
+    ///
+    /// 途中で`Future`を破棄しても、ハンドシェイク中の接続はサーバー内に保持され、次回の呼び出しで続きから進められます。
+    ///
+    /// # エラー
+    /// 接続の受け入れに失敗した場合にエラーを返します。
+    /// 接続とのハンドシェイクに失敗した場合は、その接続を閉じてエラーを返します。このエラーはその接続のみに関するもので、
+    /// 引き続き`accept`を呼び出して他の接続を受け入れられます。
+    pub async fn accept(&mut self) -> Result<AsyncClient> {
+        let mut client = std::future::poll_fn(|cx| self.poll_accept(cx)).await?;
+        client.set_connection_id(ConnectionId::new(self.next_id));
+        self.next_id += 1;
+        Ok(client)
+    }
+
+    /// 新しい接続の受け入れとハンドシェイクを進め、最初に決着した接続の結果を返します。
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<AsyncClient>> {
+        loop {
+            // 待機の登録を失わないよう、受け入れが完了するまで同じ`Future`を使い続ける
+            let listener = &self.listener;
+            let accepting = self.accepting.get_or_insert_with(|| {
+                let listener = Arc::clone(listener);
+                Box::pin(async move { listener.accept().await })
+            });
+            let Poll::Ready(accepted) = accepting.as_mut().poll(cx) else {
+                break;
+            };
+            self.accepting = None;
+            self.handshakes.push(Box::pin(AsyncClient::establish(accepted?, self.config.clone())));
+        }
+        for index in 0..self.handshakes.len() {
+            if let Poll::Ready(result) = self.handshakes[index].as_mut().poll(cx) {
+                // 完了したハンドシェイクは破棄するため、順序は保たなくてよい
+                drop(self.handshakes.swap_remove(index));
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    }
+
+    /// 受け入れた接続に適用されるプロトコルの設定を取得します。
+    pub fn get_config(&self) -> &ProtocolConfig {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ProtocolError, ProtocolErrorKind, HELLO_LEN};
+    use crate::{Client, Server};
+    use std::thread;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    /// 名前のサーバーに接続し、ハンドシェイクを行わないストリームを返します。
+    async fn connect_raw(name: &str) -> LocalSocketStream {
+        LocalSocketStream::connect(name::resolve(name).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_does_not_block_other_connections() {
+        let name = format!("instance-pipe-test-async-stalled-{}", std::process::id());
+        let mut server = AsyncServer::start(&name).unwrap();
+        // ハンドシェイクメッセージを送らない相手を先に接続させる
+        let _stalled = connect_raw(&name).await;
+        let connecting = tokio::spawn({
+            let name = name.clone();
+            async move { AsyncClient::start(&name).await }
+        });
+
+        let accepted = tokio::time::timeout(Duration::from_secs(5), server.accept()).await.unwrap().unwrap();
+        let client = connecting.await.unwrap().unwrap();
+        client.send(&7u32).await.unwrap();
+        assert_eq!(accepted.recv::<u32>().await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_is_reported_for_that_connection_only() {
+        let name = format!("instance-pipe-test-async-bad-hello-{}", std::process::id());
+        let mut server = AsyncServer::start(&name).unwrap();
+        let mut bad = connect_raw(&name).await;
+        bad.write_all(&[0u8; HELLO_LEN]).await.unwrap();
+
+        let error = server.accept().await.err().unwrap();
+        assert_eq!(ProtocolError::kind_of(&error), Some(ProtocolErrorKind::BadMagic));
+
+        let connecting = tokio::spawn({
+            let name = name.clone();
+            async move { AsyncClient::start(&name).await }
+        });
+        let accepted = server.accept().await.unwrap();
+        connecting.await.unwrap().unwrap();
+        assert!(accepted.get_connection_id().is_some());
+    }
+
+    #[tokio::test]
+    async fn sync_server_talks_to_async_client() {
+        let name = format!("instance-pipe-test-sync-server-{}", std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let serving = thread::spawn(move || {
+            let client = server.accept().unwrap();
+            let message: String = client.recv().unwrap();
+            client.send(&format!("{} back", message)).unwrap();
+        });
+
+        let client = AsyncClient::start(&name).await.unwrap();
+        client.send(&"hello".to_string()).await.unwrap();
+        assert_eq!(client.recv::<String>().await.unwrap(), "hello back");
+        serving.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_server_talks_to_sync_client() {
+        let name = format!("instance-pipe-test-async-server-{}", std::process::id());
+        let mut server = AsyncServer::start(&name).unwrap();
+        let connecting = thread::spawn({
+            let name = name.clone();
+            move || {
+                let client = Client::start(&name).unwrap();
+                client.send(&"hello".to_string()).unwrap();
+                client.recv::<String>().unwrap()
+            }
+        });
+
+        let accepted = server.accept().await.unwrap();
+        let message: String = accepted.recv().await.unwrap();
+        accepted.send(&format!("{} back", message)).await.unwrap();
+        assert_eq!(connecting.join().unwrap(), "hello back");
+    }
+}
@@ -0,0 +1,233 @@
+use crate::instance::async_client::AsyncClient;
+use crate::instance::event::DisconnectReason;
+use crate::protocol::codec::Codec;
+use crate::protocol::{self, Frame, OversizePolicy, ProtocolError, ProtocolErrorKind};
+use futures_core::Stream;
+use futures_sink::Sink;
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use std::io::{self, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// 実行中の送受信。
+type Pending<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+/// 受信したメッセージを順に返す[`Stream`]。
+///
+/// [`AsyncClient::into_stream`]で作成します。各要素は[`AsyncClient::recv`]の結果で、
+/// デシリアライズに失敗したメッセージはエラーとして返され、後続のメッセージの受信は継続されます。
+/// 相手が接続を閉じるとストリームは終了します。接続が異常終了した場合は、そのエラーを返してから終了します。
+pub struct MessageStream<T> {
+    client: AsyncClient,
+    pending: Option<Pending<T>>,
+    finished: bool,
+}
+
+/// メッセージを送信する[`Sink`]。
+///
+/// [`AsyncClient::into_sink`]で作成します。メッセージは1つずつ送信され、
+/// 前のメッセージの書き込みが完了するまで次のメッセージは受け付けられません。
+/// シンクを閉じると送信側が閉じられ、相手には接続の終了として通知されます。
+pub struct MessageSink {
+    client: AsyncClient,
+    pending: Option<Pending<()>>,
+    closed: bool,
+}
+
+impl AsyncClient {
+    /// 受信したメッセージを`T`型として順に返すストリームに変換します。
+    ///
+    /// 送信も行う場合は、クローンしたクライアントをストリームに変換してください。
+    pub fn into_stream<T: DeserializeOwned + Send + 'static>(self) -> MessageStream<T> {
+        MessageStream {
+            client: self,
+            pending: None,
+            finished: false,
+        }
+    }
+
+    /// メッセージを送信するシンクに変換します。
+    ///
+    /// シンクは`Serialize`を実装する任意の型のメッセージを受け付けます。
+    pub fn into_sink(self) -> MessageSink {
+        MessageSink {
+            client: self,
+            pending: None,
+            closed: false,
+        }
+    }
+}
+
+impl<T> MessageStream<T> {
+    /// 元のクライアントを参照します。
+    pub fn get_ref(&self) -> &AsyncClient {
+        &self.client
+    }
+
+    /// ストリームを元のクライアントに戻します。
+    ///
+    /// 受信の途中で戻した場合も、読み込み済みのバイト列はクライアント内に保持されます。
+    pub fn into_inner(self) -> AsyncClient {
+        self.client
+    }
+
+    /// 受信エラーの後も、ストリームを継続できるかどうかを判定します。
+    fn is_fatal(&self, error: &io::Error) -> bool {
+        DisconnectReason::from_error(error).is_some()
+            || (ProtocolError::kind_of(error) == Some(ProtocolErrorKind::FrameTooLarge)
+                && self.client.get_config().get_oversize_policy() == OversizePolicy::Close)
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> Stream for MessageStream<T> {
+    type Item = Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.finished {
+            return Poll::Ready(None);
+        }
+        let client = &this.client;
+        let pending = this.pending.get_or_insert_with(|| {
+            let client = client.clone();
+            Box::pin(async move { client.recv::<T>().await })
+        });
+        let result = std::task::ready!(pending.as_mut().poll(cx));
+        this.pending = None;
+        match result {
+            Ok(message) => Poll::Ready(Some(Ok(message))),
+            Err(e) if this.is_fatal(&e) => {
+                this.finished = true;
+                if DisconnectReason::from_error(&e) == Some(DisconnectReason::Closed) {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(Err(e)))
+                }
+            }
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+impl MessageSink {
+    /// 元のクライアントを参照します。
+    pub fn get_ref(&self) -> &AsyncClient {
+        &self.client
+    }
+
+    /// シンクを元のクライアントに戻します。
+    ///
+    /// 書き込みの途中で戻すとフレームが途切れるため、先にシンクをフラッシュしてください。
+    pub fn into_inner(self) -> AsyncClient {
+        self.client
+    }
+
+    /// 実行中の書き込みがあれば、完了するまで進めます。
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let Some(pending) = self.pending.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = std::task::ready!(pending.as_mut().poll(cx));
+        self.pending = None;
+        Poll::Ready(result)
+    }
+}
+
+impl<T: Serialize> Sink<T> for MessageSink {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: T) -> Result<()> {
+        let this = self.get_mut();
+        if this.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Sink has been closed"));
+        }
+        let config = this.client.get_config();
+        let bytes = protocol::encode_frame(&Frame::data(config.get_codec().encode(&message)?), config)?;
+        let client = this.client.clone();
+        this.pending = Some(Box::pin(async move { client.write_bytes(&bytes).await }));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        std::task::ready!(this.poll_pending(cx))?;
+        if !this.closed {
+            this.closed = true;
+            let client = this.client.clone();
+            this.pending = Some(Box::pin(async move { client.shutdown_writer().await }));
+        }
+        this.poll_pending(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AsyncServer;
+    use std::future::poll_fn;
+
+    /// サーバーと接続し、サーバー側とクライアント側のクライアントを返します。
+    async fn connect(test: &str) -> (AsyncServer, AsyncClient, AsyncClient) {
+        let name = format!("instance-pipe-test-{}-{}", test, std::process::id());
+        let mut server = AsyncServer::start(&name).unwrap();
+        let (accepted, client) = tokio::join!(server.accept(), AsyncClient::start(&name));
+        (server, accepted.unwrap(), client.unwrap())
+    }
+
+    /// ストリームから次の要素を受信します。
+    async fn next<T: DeserializeOwned + Send + 'static>(stream: &mut MessageStream<T>) -> Option<Result<T>> {
+        poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn stream_yields_messages_in_order() {
+        let (_server, accepted, client) = connect("stream-order").await;
+        for n in 0..5u32 {
+            accepted.send(&n).await.unwrap();
+        }
+
+        let mut stream = client.into_stream::<u32>();
+        for n in 0..5u32 {
+            assert_eq!(next(&mut stream).await.unwrap().unwrap(), n);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_ends_when_peer_closes() {
+        let (_server, accepted, client) = connect("stream-close").await;
+        accepted.send(&1u32).await.unwrap();
+        drop(accepted);
+
+        let mut stream = client.into_stream::<u32>();
+        assert_eq!(next(&mut stream).await.unwrap().unwrap(), 1);
+        assert!(next(&mut stream).await.is_none());
+        assert!(next(&mut stream).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn close_flushes_and_shuts_down_the_writer() {
+        let (_server, accepted, client) = connect("sink-close").await;
+        let mut sink = client.into_sink();
+
+        poll_fn(|cx| Sink::<u32>::poll_ready(Pin::new(&mut sink), cx)).await.unwrap();
+        Pin::new(&mut sink).start_send(7u32).unwrap();
+        poll_fn(|cx| Sink::<u32>::poll_close(Pin::new(&mut sink), cx)).await.unwrap();
+
+        assert_eq!(accepted.recv::<u32>().await.unwrap(), 7);
+        let error = accepted.recv::<u32>().await.err().unwrap();
+        assert_eq!(DisconnectReason::from_error(&error), Some(DisconnectReason::Closed));
+        assert_eq!(
+            Pin::new(&mut sink).start_send(8u32).err().unwrap().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
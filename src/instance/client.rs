@@ -1,99 +1,359 @@
-use crate::instance::event::{Event, EventHandler};
-use crate::protocol;
+use crate::instance::event::{DisconnectReason, Event, EventHandler};
+use crate::protocol::codec::Codec;
+use crate::protocol::{self, Capabilities, Frame, FrameDecoder, FrameKind, Hello, ProtocolConfig, HELLO_LEN};
+#[cfg(unix)]
+use crate::instance::credentials;
+use crate::instance::name;
+use crate::instance::readiness::{self, Interest};
+use crate::instance::pubsub;
+use crate::instance::server::ConnectionId;
 use interprocess::local_socket::prelude::*;
 use interprocess::local_socket::traits::Stream;
-use interprocess::local_socket::{GenericNamespaced, NameType, ToFsName, ToNsName};
-use interprocess::os::unix::local_socket::FilesystemUdSocket;
 use serde::{Deserialize, Serialize};
-use std::io::{self, Result};
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Result, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+/// 1回の読み込みで受信を試みる最大バイト数。
+const READ_CHUNK_SIZE: usize = 4096;
+/// サーバーの起動を待つ間、接続を再試行する最初の間隔。
+const CONNECT_RETRY_MIN: Duration = Duration::from_millis(1);
+/// サーバーの起動を待つ間、接続を再試行する間隔の上限。
+const CONNECT_RETRY_MAX: Duration = Duration::from_millis(10);
+/// 受け取り手を待っているRPCリクエスト・配信・フィンガープリントのフレームを、種類ごとに保持する最大数。
+///
+/// メッセージ以外のフレームは、アプリケーションが対応する機能を使用していなければ取り出されないため、
+/// 上限を超えて届いたフレームは破棄し、[`ProtocolErrorKind::QueueFull`](protocol::ProtocolErrorKind::QueueFull)として報告します。
+pub const MAX_QUEUED_FRAMES: usize = 1024;
+/// 期限付きの送信で、他のスレッドの送信が終わったかを確認し直す間隔。
+const WRITE_LOCK_RETRY: Duration = Duration::from_millis(1);
 
 /// サーバーに接続するためのクライアント構造体。
+///
+/// クローンしたクライアントは同じ接続を共有します。
+/// 複数のスレッドから同時に送受信しても、フレームが混ざることはありません。
 #[derive(Clone)]
 pub struct Client {
-    stream: Arc<LocalSocketStream>,
+    shared: Arc<Shared>,
+    config: ProtocolConfig,
+    negotiated: Hello,
+    connection_id: Option<ConnectionId>,
     event_handler: EventHandler,
     timeout: Duration,
 }
 
+/// クローン間で共有される接続の状態。
+struct Shared {
+    stream: LocalSocketStream,
+    /// ストリームを非ブロッキングモードに切り替え済みかどうか。
+    nonblocking: AtomicBool,
+    /// ストリームに対するシステムコールを1つずつ実行するためのロック。
+    io_lock: Mutex<()>,
+    /// 1つのフレームを途切れずに書き込むためのロック。
+    write_lock: Mutex<()>,
+    /// 受信済みのフレームと、読み込みを担当しているスレッドの有無。
+    state: Mutex<ReadState>,
+    /// 新しいフレームの受信や読み込み担当の交代を通知します。
+    readable: Condvar,
+    /// 次に使用するRPCの相関ID。
+    next_id: AtomicU64,
+    /// 切断をリスナーに通知済みかどうか。
+    disconnect_notified: AtomicBool,
+}
+
+/// 受信側の状態。
+struct ReadState {
+    decoder: FrameDecoder,
+    /// エラーにより振り分けを中断し、デコーダーに完全なフレームが残っている可能性があるかどうか。
+    undispatched: bool,
+    /// 受け取り手を待っているメッセージおよびRPCリクエスト。
+    inbox: VecDeque<Frame>,
+    /// 応答を待っているRPC呼び出しの相関ID。
+    pending: HashSet<u64>,
+    /// 呼び出し元に渡す前のRPCレスポンス。
+    responses: HashMap<u64, Vec<u8>>,
+    /// 受信したが、まだレスポンスを返していないRPCリクエストの相関ID。
+    unanswered: HashSet<u64>,
+    /// 相手が購読しているトピックのパターン。
+    subscriptions: BTreeSet<String>,
+    /// いずれかのスレッドがストリームから読み込み中かどうか。
+    reading: bool,
+    /// 相手がgoodbyeフレームで終了を通知したかどうか。
+    goodbye: bool,
+    /// 接続が切断された理由。切断されていなければNone。
+    disconnected: Option<DisconnectReason>,
+}
+
 impl From<LocalSocketStream> for Client {
     /// `LocalSocketStream`から`Client`を生成します。
+    ///
+    /// ハンドシェイクは行われないため、ハンドシェイク済みのストリームに対して使用してください。
     fn from(value: LocalSocketStream) -> Self {
-        Self {
-            stream: Arc::new(value),
-            event_handler: EventHandler::new(),
-            timeout: Duration::from_millis(50), // Default timeout of 50ms
-        }
+        Self::with_config(value, ProtocolConfig::default())
     }
 }
 
 impl Client {
     /// 指定された名前のサーバーに接続を開始します。
     ///
-    /// 名前付きパイプまたはソケットを使用して接続を確立します。
+    /// 名前付きパイプまたはソケットを使用して接続を確立し、
+    /// サーバーとハンドシェイクを行ってプロトコルバージョンと機能を決定します。
     ///
     /// # 引数
     /// - `name`: 接続するサーバーのパイプまたはソケット名。
     ///
     /// # エラー
-    /// 接続に失敗した場合や、サポートされていないソケットタイプの場合にエラーを返します。
+    /// 接続に失敗した場合や、サポートされていないソケットタイプの場合、
+    /// サーバーとプロトコルバージョンの互換性がない場合にエラーを返します。
     pub fn start(name: &str) -> Result<Self> {
-        let name = socket_name(name);
-        let socket_name = if GenericNamespaced::is_supported() {
-            name.to_ns_name::<GenericNamespaced>()?
-        } else if FilesystemUdSocket::is_supported() {
-            name.to_fs_name::<FilesystemUdSocket>()?
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "Neither namespaced nor filesystem-based sockets are supported",
-            ));
+        Self::start_with(name, ProtocolConfig::default())
+    }
+
+    /// 指定されたプロトコル設定でサーバーに接続を開始します。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    /// - `config`: この接続に適用するプロトコルの設定。
+    ///
+    /// # エラー
+    /// 接続に失敗した場合や、サポートされていないソケットタイプの場合、
+    /// サーバーとプロトコルバージョンの互換性がない場合にエラーを返します。
+    pub fn start_with(name: &str, config: ProtocolConfig) -> Result<Self> {
+        let stream = LocalSocketStream::connect(name::resolve(name)?)?;
+        let mut client = Self::with_config(stream, config);
+        client.handshake()?;
+        Ok(client)
+    }
+
+    /// サーバーが起動するのを待って接続します。
+    ///
+    /// サーバーがまだ待ち受けていない場合（`NotFound`・`ConnectionRefused`）は、
+    /// `timeout`が経過するまで短い間隔で接続を再試行します。間隔は1ミリ秒から始まり、最大10ミリ秒まで延びます。
+    /// 名前空間ソケットや名前付きパイプはファイルシステム上のディレクトリに現れないため、作成を監視せずに再試行します。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    /// - `timeout`: サーバーの起動を待つ最大時間。ハンドシェイクには別途設定のタイムアウトが適用されます。
+    ///
+    /// # エラー
+    /// 時間内にサーバーが起動しなかった場合は最後の接続エラーを返します。
+    /// それ以外の接続エラーや、ハンドシェイクに失敗した場合はすぐにエラーを返します。
+    pub fn connect_timeout(name: &str, timeout: Duration) -> Result<Self> {
+        Self::connect_timeout_with(name, ProtocolConfig::default(), timeout)
+    }
+
+    /// 指定されたプロトコル設定で、サーバーが起動するのを待って接続します。
+    ///
+    /// 再試行の動作は[`connect_timeout`](Client::connect_timeout)と同じです。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    /// - `config`: この接続に適用するプロトコルの設定。
+    /// - `timeout`: サーバーの起動を待つ最大時間。
+    ///
+    /// # エラー
+    /// 時間内にサーバーが起動しなかった場合は最後の接続エラーを返します。
+    /// それ以外の接続エラーや、ハンドシェイクに失敗した場合はすぐにエラーを返します。
+    pub fn connect_timeout_with(name: &str, config: ProtocolConfig, timeout: Duration) -> Result<Self> {
+        let deadline = Instant::now() + timeout;
+        let name = name::resolve(name)?;
+        let mut interval = CONNECT_RETRY_MIN;
+        let stream = loop {
+            match LocalSocketStream::connect(name.borrow()) {
+                Ok(stream) => break stream,
+                Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(e);
+                    }
+                    std::thread::sleep(interval.min(remaining));
+                    interval = (interval * 2).min(CONNECT_RETRY_MAX);
+                }
+                Err(e) => return Err(e),
+            }
         };
-        let stream = LocalSocketStream::connect(socket_name)?;
-        Ok(Self {
-            stream: Arc::new(stream),
+        let mut client = Self::with_config(stream, config);
+        client.handshake()?;
+        Ok(client)
+    }
+
+    /// 指定されたプロトコル設定で`LocalSocketStream`から`Client`を生成します。
+    pub(crate) fn with_config(stream: LocalSocketStream, config: ProtocolConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                stream,
+                nonblocking: AtomicBool::new(false),
+                io_lock: Mutex::new(()),
+                write_lock: Mutex::new(()),
+                state: Mutex::new(ReadState {
+                    decoder: FrameDecoder::with_config(config.clone()),
+                    undispatched: false,
+                    inbox: VecDeque::new(),
+                    pending: HashSet::new(),
+                    responses: HashMap::new(),
+                    unanswered: HashSet::new(),
+                    subscriptions: BTreeSet::new(),
+                    reading: false,
+                    goodbye: false,
+                    disconnected: None,
+                }),
+                readable: Condvar::new(),
+                next_id: AtomicU64::new(1),
+                disconnect_notified: AtomicBool::new(false),
+            }),
+            config,
+            negotiated: Hello::local(),
+            connection_id: None,
             event_handler: EventHandler::new(),
             timeout: Duration::from_millis(50), // Default timeout of 50ms
-        })
+        }
+    }
+
+    /// 相手とハンドシェイクメッセージを交換し、接続で使用するバージョンと機能を決定します。
+    ///
+    /// 両端が先に自身のメッセージを送信してから相手のメッセージを読むため、
+    /// クライアントとサーバーで同じ処理を使用できます。
+    /// ハンドシェイクを行わないNDJSONモードでは何もしません。
+    ///
+    /// # エラー
+    /// 相手がタイムアウト時間内に応答しない場合や、マジックバイト・バージョンが一致しない場合にエラーを返します。
+    pub(crate) fn handshake(&mut self) -> Result<()> {
+        if !self.send_hello()? {
+            return Ok(());
+        }
+        let deadline = Instant::now() + self.config.get_handshake_timeout();
+        let mut bytes = [0u8; HELLO_LEN];
+        let mut filled = 0;
+        while !self.read_hello(&mut bytes, &mut filled)? {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Peer did not complete the handshake in time",
+                ));
+            }
+            self.wait_stream(Interest::Read, Some(remaining))?;
+        }
+        self.finish_handshake(&bytes)
+    }
+
+    /// ハンドシェイクを開始し、自身のハンドシェイクメッセージを送信します。
+    ///
+    /// 設定で要求されている場合は、送信する前に相手が同じユーザーであることを確認します。
+    /// ハンドシェイクを行わないNDJSONモードでは、機能なしとして接続を確定させてfalseを返します。
+    ///
+    /// # エラー
+    /// 相手が異なるユーザーの場合や、送信に失敗した場合にエラーを返します。
+    pub(crate) fn send_hello(&mut self) -> Result<bool> {
+        if self.config.get_require_same_user() {
+            self.verify_peer()?;
+        }
+        if !self.config.uses_handshake() {
+            // NDJSONモードでは相手が追加機能に対応していることを確認できないため、機能なしとして扱う
+            self.negotiated.capabilities = Capabilities::NONE;
+            return Ok(false);
+        }
+        self.write_bytes(&Hello::local().encode())?;
+        Ok(true)
+    }
+
+    /// 相手のハンドシェイクメッセージを、待たずに読み込める分だけ`bytes[*filled..]`に読み込みます。
+    ///
+    /// メッセージの長さを超えて読み込むことはないため、続くフレームのバイト列は失われません。
+    /// メッセージがすべて揃った場合にtrueを返します。
+    ///
+    /// # エラー
+    /// 相手が接続を閉じた場合や、読み込みに失敗した場合にエラーを返します。
+    pub(crate) fn read_hello(&self, bytes: &mut [u8; HELLO_LEN], filled: &mut usize) -> Result<bool> {
+        while *filled < HELLO_LEN {
+            match self.with_stream(|mut stream| stream.read(&mut bytes[*filled..])) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => *filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// 受信した相手のハンドシェイクメッセージと照合し、接続で使用するバージョンと機能を決定します。
+    ///
+    /// # エラー
+    /// マジックバイト・バージョンが一致しない場合にエラーを返します。
+    pub(crate) fn finish_handshake(&mut self, bytes: &[u8; HELLO_LEN]) -> Result<()> {
+        self.negotiated = Hello::local().negotiate(&Hello::decode(bytes)?)?;
+        Ok(())
     }
 
     /// クライアントを停止し、接続を閉じます。
+    ///
+    /// 相手がgoodbyeフレームに対応していれば終了を通知してから、ストリームの送受信を停止します。
+    /// 相手は[`DisconnectReason::Shutdown`]による切断として、対応していなければ通常の切断として検出します。
+    /// 相手が読み込まないなどの理由でタイムアウト時間内に通知を送信できない場合は、通知せずに閉じます。
+    /// 同じ接続を共有するクローンも以降は送受信できなくなります。既に停止している場合は何もしません。
+    ///
+    /// # エラー
+    /// goodbyeフレームの送信に失敗した場合にエラーを返します。切断済みやタイムアウトによる失敗は無視されます。
     pub fn stop(&mut self) -> Result<()> {
-        // LocalSocketStreamは明示的なshutdownを持たないため、ドロップで対応
-        Ok(())
+        if self.get_disconnect_reason().is_some() {
+            return Ok(());
+        }
+        let goodbye = if self.get_capabilities().contains(Capabilities::GOODBYE) {
+            let goodbye = Frame {
+                kind: FrameKind::Goodbye,
+                payload: Vec::new(),
+            };
+            self.write_frame_until(&goodbye, Instant::now() + self.timeout)
+        } else {
+            Ok(())
+        };
+        self.close();
+        match goodbye {
+            Err(e) if e.kind() != io::ErrorKind::TimedOut && DisconnectReason::from_error(&e).is_none() => Err(e),
+            _ => Ok(()),
+        }
     }
 
     /// サーバーからのイベントをポーリングします。
     ///
     /// 非ブロッキングでメッセージを受信し、イベントとして返します。
     /// タイムアウト時間内にメッセージがなければNoneを返します。
+    /// 途中まで届いたフレームはクライアント内に保持され、次回以降のポーリングで続きから組み立てられます。
+    ///
+    /// 相手がgoodbyeフレームで終了を通知した場合は、それまでに届いたメッセージの後に[`Event::Goodbye`]を返します。
+    /// 相手が接続を閉じた場合は[`Event::Disconnected`]を、プロトコル違反を検出した場合は
+    /// [`Event::ProtocolError`]を返します。切断後のポーリングでも[`Event::Disconnected`]が返されます。
     ///
     /// # エラー
-    /// メッセージの受信またはデシリアライズに失敗した場合にエラーを返します。
-    pub fn poll_event<T: for<'a> Deserialize<'a>>(&mut self) -> Result<Option<Event<T>>> {
-        self.stream.set_nonblocking(true)?;
-        let start = std::time::Instant::now();
-        loop {
-            match protocol::recv_message(&mut &*self.stream) {
-                Ok(message) => {
-                    self.stream.set_nonblocking(false)?;
-                    return Ok(Some(Event::MessageReceived(message)));
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    if start.elapsed() >= self.timeout {
-                        self.stream.set_nonblocking(false)?;
-                        return Ok(None);
-                    }
-                    std::thread::sleep(Duration::from_millis(10));
-                    continue;
-                }
-                Err(e) => {
-                    self.stream.set_nonblocking(false)?;
-                    return Err(e);
-                }
-            }
+    /// メッセージのデシリアライズに失敗した場合や、その他のI/Oエラーが発生した場合にエラーを返します。
+    pub fn poll_event<T: for<'a> Deserialize<'a> + 'static>(&mut self) -> Result<Option<Event<T>>> {
+        let deadline = Instant::now() + self.timeout;
+        let frame = self.wait_for(Some(deadline), |state| {
+            let index = state
+                .inbox
+                .iter()
+                .position(|frame| matches!(frame.kind, FrameKind::Data | FrameKind::Goodbye))?;
+            state.inbox.remove(index)
+        });
+        let event = match frame {
+            Ok(Some(frame)) if frame.kind == FrameKind::Goodbye => Event::Goodbye {
+                connection: self.connection_id,
+            },
+            Ok(Some(frame)) => match self.config.get_codec().decode(&frame.payload) {
+                Ok(message) => Event::MessageReceived(message),
+                Err(e) => Event::from_error(e, self.connection_id)?,
+            },
+            Ok(None) => return Ok(None),
+            Err(e) => Event::from_error(e, self.connection_id)?,
+        };
+        match &event {
+            Event::Disconnected { reason, .. } => self.notify_disconnected(*reason),
+            _ => self.event_handler.notify(&event),
         }
+        Ok(Some(event))
     }
 
     /// サーバーにメッセージを送信します。
@@ -104,21 +364,24 @@ impl Client {
     /// # エラー
     /// メッセージのシリアライズまたは送信に失敗した場合にエラーを返します。
     pub fn send<T: Serialize>(&self, message: &T) -> Result<()> {
-        let stream_clone = self.stream.clone();
-        protocol::send_message(&mut &*stream_clone, message)?;
-        self.event_handler.notify(Event::<()>::MessageSent);
+        let encoded = self.config.get_codec().encode(message)?;
+        self.write_frame(&Frame::data(encoded))?;
+        self.event_handler.notify(&Event::<()>::MessageSent);
         Ok(())
     }
 
     /// サーバーからメッセージを受信します。
     ///
     /// # エラー
-    /// メッセージの受信またはデシリアライズに失敗した場合にエラーを返します。
-    pub fn recv<T: for<'a> Deserialize<'a> + Clone>(&self) -> Result<T> {
-        let stream_clone = self.stream.clone();
-        let message: T = protocol::recv_message(&mut &*stream_clone)?;
+    /// メッセージの受信またはデシリアライズに失敗した場合や、
+    /// フレームが最大サイズを超える場合にエラーを返します。
+    pub fn recv<T: for<'a> Deserialize<'a> + Clone + 'static>(&self) -> Result<T> {
+        let frame = self
+            .next_frame(FrameKind::Data, None)?
+            .ok_or(io::ErrorKind::TimedOut)?;
+        let message: T = self.config.get_codec().decode(&frame.payload)?;
         self.event_handler
-            .notify(Event::MessageReceived(message.clone()));
+            .notify(&Event::MessageReceived(message.clone()));
         Ok(message)
     }
 
@@ -134,16 +397,634 @@ impl Client {
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.timeout = timeout;
     }
+
+    /// この接続に適用されているプロトコルの設定を取得します。
+    pub fn get_config(&self) -> &ProtocolConfig {
+        &self.config
+    }
+
+    /// ハンドシェイクで決定したプロトコルバージョンを取得します。
+    pub fn get_protocol_version(&self) -> u16 {
+        self.negotiated.version
+    }
+
+    /// ハンドシェイクで決定した、両端が対応している機能を取得します。
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.negotiated.capabilities
+    }
+
+    /// サーバーが受け入れた接続に割り当てた接続IDを取得します。
+    ///
+    /// [`Server`](crate::Server)が受け入れた接続以外ではNoneを返します。
+    pub fn get_connection_id(&self) -> Option<ConnectionId> {
+        self.connection_id
+    }
+
+    /// 相手との接続が維持されているかどうかを返します。
+    ///
+    /// 他のスレッドが受信中でなければ、届いているバイト列を待たずに受信して切断を検出します。
+    /// その際に受信したメッセージは失われず、後続のポーリングで受け取れます。
+    pub fn is_connected(&self) -> bool {
+        // 切断以外の受信エラーは、受信を担当する呼び出し元に改めて返されるため無視する
+        let _ = self.pump();
+        self.get_disconnect_reason().is_none()
+    }
+
+    /// 接続が切断されたことを検出済みであれば、その理由を返します。
+    pub fn get_disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.lock_state().disconnected
+    }
+
+    /// 相手がgoodbyeフレームで接続の終了を通知したかどうかを返します。
+    ///
+    /// 通知は[`poll_event`](Client::poll_event)でも[`Event::Goodbye`]として返されます。
+    /// 通知を受けた後も、相手が接続を閉じるまでは送受信できます。処理中のやり取りを終えたら
+    /// [`stop`](Client::stop)で接続を閉じてください。相手が接続を閉じると、
+    /// 切断の理由は[`DisconnectReason::Shutdown`]になります。
+    pub fn is_shutting_down(&self) -> bool {
+        let _ = self.pump();
+        self.lock_state().goodbye
+    }
+
+    /// 届いているバイト列を待たずに受信し、処理中のやり取りがあるかどうかを返します。
+    ///
+    /// 受け取り手を待っているか、まだレスポンスを返していないRPCリクエストと、
+    /// 相手の応答を待っているRPC呼び出しを処理中とみなします。
+    pub(crate) fn has_in_flight(&self) -> bool {
+        let _ = self.pump();
+        let state = self.lock_state();
+        !state.unanswered.is_empty() || !state.pending.is_empty()
+    }
+
+    /// この接続のイベントを通知するハンドラーを返します。
+    ///
+    /// リスナーを登録すると、メッセージの送受信や切断の際に呼び出されます。
+    pub fn event_handler(&self) -> &EventHandler {
+        &self.event_handler
+    }
+
+    /// 接続IDを設定します。
+    pub(crate) fn set_connection_id(&mut self, id: ConnectionId) {
+        self.connection_id = Some(id);
+    }
+
+    /// イベントを通知するハンドラーを置き換えます。
+    pub(crate) fn set_event_handler(&mut self, event_handler: EventHandler) {
+        self.event_handler = event_handler;
+    }
+
+    /// 切断をリスナーに通知します。同じ接続について通知するのは最初の1回のみです。
+    pub(crate) fn notify_disconnected(&self, reason: DisconnectReason) {
+        if !self.shared.disconnect_notified.swap(true, Ordering::AcqRel) {
+            self.event_handler.notify(&Event::<()>::Disconnected {
+                connection: self.connection_id,
+                reason,
+            });
+        }
+    }
+
+    /// 相手に通知せずにストリームの送受信を停止し、終了処理による切断として記録します。
+    pub(crate) fn close(&self) {
+        // 停止による切断を先に記録し、読み込み中のスレッドが通常の切断として記録しないようにする
+        self.lock_state().disconnected.get_or_insert(DisconnectReason::Shutdown);
+        self.shutdown_stream();
+        self.shared.readable.notify_all();
+        self.notify_disconnected(DisconnectReason::Shutdown);
+    }
+
+    /// フレームを1つ送信します。
+    ///
+    /// 他のクローンが同時に送信していても、フレームの途中に別のフレームが挟まることはありません。
+    pub(crate) fn write_frame(&self, frame: &Frame) -> Result<()> {
+        let bytes = protocol::encode_frame(frame, &self.config)?;
+        self.write_bytes(&bytes)
+    }
+
+    /// 新しいRPCの相関IDを割り当て、そのレスポンスを受け取れるように登録します。
+    pub(crate) fn begin_call(&self) -> u64 {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock_state().pending.insert(id);
+        id
+    }
+
+    /// 登録済みのRPC呼び出しを取り消します。以降に届いたレスポンスは破棄されます。
+    pub(crate) fn cancel_call(&self, id: u64) {
+        let mut state = self.lock_state();
+        state.pending.remove(&id);
+        state.responses.remove(&id);
+    }
+
+    /// 相手から受信したRPCリクエストにレスポンスを返したことを記録します。
+    pub(crate) fn answered(&self, id: u64) {
+        self.lock_state().unanswered.remove(&id);
+    }
+
+    /// 指定された相関IDのRPCレスポンスを待ち、相関IDを除いた本体を返します。
+    ///
+    /// 期限までに届かなかった場合はNoneを返します。
+    pub(crate) fn wait_response(&self, id: u64, deadline: Instant) -> Result<Option<Vec<u8>>> {
+        let response = self.wait_for(Some(deadline), |state| {
+            let response = state.responses.remove(&id);
+            if response.is_some() {
+                state.pending.remove(&id);
+            }
+            response
+        });
+        if !matches!(response, Ok(Some(_))) {
+            self.cancel_call(id);
+        }
+        response
+    }
+
+    /// 相手が購読しているパターンのいずれかが、指定されたトピックに一致するかどうかを返します。
+    ///
+    /// 届いている購読の変更を反映するため、他のスレッドが受信中でなければ待たずに受信を試みます。
+    pub(crate) fn is_subscribed(&self, topic: &str) -> bool {
+        let _ = self.pump();
+        self.lock_state()
+            .subscriptions
+            .iter()
+            .any(|pattern| pubsub::topic_matches(pattern, topic))
+    }
+
+    /// 指定された種類のフレームを、受信順に1つ取り出します。
+    ///
+    /// `deadline`がNoneの場合は届くまで待ちます。期限までに届かなかった場合はNoneを返します。
+    pub(crate) fn next_frame(&self, kind: FrameKind, deadline: Option<Instant>) -> Result<Option<Frame>> {
+        self.wait_for(deadline, |state| {
+            let index = state.inbox.iter().position(|frame| frame.kind == kind)?;
+            state.inbox.remove(index)
+        })
+    }
+
+    /// 届いているバイト列を待たずに受信し、指定された種類のフレームがあれば1つ取り出します。
+    pub(crate) fn try_next_frame(&self, kind: FrameKind) -> Result<Option<Frame>> {
+        self.pump()?;
+        self.next_frame(kind, Some(Instant::now()))
+    }
+
+    /// `take`が値を返すまでフレームを受信します。
+    ///
+    /// ストリームから読み込むのは常に1つのスレッドのみで、他のスレッドは読み込み担当が
+    /// 受信したフレームを振り分けるのを待ちます。これにより、クローンしたクライアントから
+    /// 同時に受信しても、それぞれが自分宛てのフレームだけを受け取れます。
+    fn wait_for<T>(
+        &self,
+        deadline: Option<Instant>,
+        mut take: impl FnMut(&mut ReadState) -> Option<T>,
+    ) -> Result<Option<T>> {
+        let mut state = self.lock_state();
+        loop {
+            if let Some(value) = take(&mut state) {
+                return Ok(Some(value));
+            }
+            if state.undispatched {
+                // 前回のエラーの後に届いていたフレームを、ストリームから読み込む前に振り分ける
+                state.undispatched = false;
+                self.dispatch(&mut state)?;
+                continue;
+            }
+            if let Some(reason) = state.disconnected {
+                return Err(reason.into());
+            }
+            let remaining = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => Some(remaining),
+                    _ => return Ok(None),
+                },
+                None => None,
+            };
+
+            if state.reading {
+                state = match remaining {
+                    Some(remaining) => {
+                        self.shared
+                            .readable
+                            .wait_timeout(state, remaining)
+                            .unwrap_or_else(|poisoned| poisoned.into_inner())
+                            .0
+                    }
+                    None => self
+                        .shared
+                        .readable
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()),
+                };
+                continue;
+            }
+
+            // 読み込み担当になり、状態のロックを解放してから読み込む
+            state.reading = true;
+            drop(state);
+            let read = self.read_chunk(remaining);
+            state = self.lock_state();
+            self.finish_read(&mut state, read)?;
+        }
+    }
+
+    /// 他のスレッドが読み込み中でなければ、届いているバイト列を待たずにすべて受信します。
+    ///
+    /// 受信したフレームは通常どおり振り分けられ、後続のポーリングで受け取れます。
+    fn pump(&self) -> Result<()> {
+        loop {
+            let mut state = self.lock_state();
+            if state.disconnected.is_some() || state.reading {
+                return Ok(());
+            }
+            state.reading = true;
+            drop(state);
+            let read = self.read_chunk(Some(Duration::ZERO));
+            let more = matches!(&read, Ok(Some(bytes)) if !bytes.is_empty());
+            self.finish_read(&mut self.lock_state(), read)?;
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+
+    /// 読み込み担当を終了し、読み込んだバイト列を振り分けて待機中のスレッドに通知します。
+    fn finish_read(&self, state: &mut ReadState, read: Result<Option<Vec<u8>>>) -> Result<()> {
+        state.reading = false;
+        let dispatched = match read {
+            Ok(Some(bytes)) if bytes.is_empty() => {
+                let reason = if state.goodbye {
+                    DisconnectReason::Shutdown
+                } else {
+                    DisconnectReason::Closed
+                };
+                state.disconnected.get_or_insert(reason);
+                Ok(())
+            }
+            Ok(Some(bytes)) => {
+                state.decoder.extend(&bytes);
+                self.dispatch(state)
+            }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                if let Some(reason) = DisconnectReason::from_error(&e) {
+                    let reason = if state.goodbye { DisconnectReason::Shutdown } else { reason };
+                    state.disconnected.get_or_insert(reason);
+                }
+                Err(e)
+            }
+        };
+        self.shared.readable.notify_all();
+        dispatched
+    }
+
+    /// デコーダーから完全なフレームをすべて取り出し、種類ごとに振り分けます。
+    ///
+    /// 最大サイズを超えるフレームによりデコーダーが受信を停止した場合は、接続を閉じて相手に通知します。
+    /// それ以外のエラーで中断した場合は、残りのフレームを次の受信の前に振り分けます。
+    fn dispatch(&self, state: &mut ReadState) -> Result<()> {
+        let dispatched = self.dispatch_frames(state);
+        state.undispatched = dispatched.is_err() && !state.decoder.is_closed();
+        dispatched
+    }
+
+    /// デコーダーから完全なフレームを取り出して振り分け、最初のエラーで中断します。
+    fn dispatch_frames(&self, state: &mut ReadState) -> Result<()> {
+        loop {
+            let frame = state.decoder.next_frame();
+            if state.decoder.is_closed() {
+                self.shutdown_stream();
+            }
+            let Some(frame) = frame? else {
+                return Ok(());
+            };
+            if !self.negotiated.capabilities.contains(frame.kind.required_capability()) {
+                return Err(protocol::ProtocolError::new(
+                    protocol::ProtocolErrorKind::InvalidFrame,
+                    format!("Unexpected {:?} frame for a capability that was not negotiated", frame.kind),
+                )
+                .into());
+            }
+            match frame.kind {
+                FrameKind::Response => {}
+                FrameKind::Subscribe | FrameKind::Unsubscribe => {
+                    // 購読の変更は受け取り手を待たずに反映し、publishの宛先判定に使用する
+                    let pattern = String::from_utf8(frame.payload).map_err(|_| {
+                        protocol::ProtocolError::new(
+                            protocol::ProtocolErrorKind::InvalidFrame,
+                            "Topic pattern is not valid UTF-8",
+                        )
+                    })?;
+                    if frame.kind == FrameKind::Subscribe {
+                        // 長すぎるパターンは照合の負荷になるため登録しない
+                        pubsub::check_pattern(&pattern)?;
+                        state.subscriptions.insert(pattern);
+                    } else {
+                        state.subscriptions.remove(&pattern);
+                    }
+                    continue;
+                }
+                FrameKind::Goodbye => {
+                    // 相手は処理中のやり取りを終えてから接続を閉じるため、記録したうえでイベントとして渡す
+                    state.goodbye = true;
+                    state.inbox.push_back(frame);
+                    continue;
+                }
+                FrameKind::Data => {
+                    state.inbox.push_back(frame);
+                    continue;
+                }
+                _ => {
+                    // 受け取り手がいない種類のフレームが溜まり続けないよう、種類ごとに保持する数を制限する
+                    if state.inbox.iter().filter(|queued| queued.kind == frame.kind).count() >= MAX_QUEUED_FRAMES {
+                        return Err(protocol::ProtocolError::new(
+                            protocol::ProtocolErrorKind::QueueFull,
+                            format!("Dropped a {:?} frame: too many are waiting to be consumed", frame.kind),
+                        )
+                        .into());
+                    }
+                    if frame.kind == FrameKind::Request
+                        && let Some(id) = frame.payload.first_chunk::<8>()
+                    {
+                        // 終了処理がレスポンスを返し終えるまで待てるよう、相関IDを記録する
+                        state.unanswered.insert(u64::from_le_bytes(*id));
+                    }
+                    state.inbox.push_back(frame);
+                    continue;
+                }
+            }
+            let Some((id, body)) = frame.payload.split_first_chunk::<8>() else {
+                return Err(protocol::ProtocolError::new(
+                    protocol::ProtocolErrorKind::InvalidFrame,
+                    "RPC response is missing its correlation id",
+                )
+                .into());
+            };
+            let id = u64::from_le_bytes(*id);
+            // タイムアウトなどで取り消された呼び出しへのレスポンスは破棄する
+            if state.pending.contains(&id) {
+                state.responses.insert(id, body.to_vec());
+            }
+        }
+    }
+
+    /// ストリームから1回分のバイト列を読み込みます。
+    ///
+    /// データが届いていない場合は、ストリームが読み込み可能になるまで最大`wait`だけ待ってからNoneを返します。
+    /// `wait`がNoneの場合は無期限に待ちます。空のバイト列は相手が接続を閉じたことを示します。
+    fn read_chunk(&self, wait: Option<Duration>) -> Result<Option<Vec<u8>>> {
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        match self.with_stream(|mut stream| stream.read(&mut buf)) {
+            Ok(n) => Ok(Some(buf[..n].to_vec())),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if wait != Some(Duration::ZERO) {
+                    self.wait_stream(Interest::Read, wait)?;
+                }
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// バイト列を途切れずにすべて書き込みます。
+    ///
+    /// 書き込みの途中でも受信側のシステムコールが実行できるよう、ストリームのロックは
+    /// 1回の書き込みごとに解放します。
+    /// 切断を示すエラーで失敗した場合は、その理由を記録します。
+    pub(crate) fn write_bytes(&self, bytes: &[u8]) -> Result<()> {
+        self.write_bytes_until(bytes, None)
+    }
+
+    /// フレームを1つ、`deadline`までに送信します。
+    ///
+    /// 動作は[`write_bytes_until`](Client::write_bytes_until)と同じです。
+    pub(crate) fn write_frame_until(&self, frame: &Frame, deadline: Instant) -> Result<()> {
+        let bytes = protocol::encode_frame(frame, &self.config)?;
+        self.write_bytes_until(&bytes, Some(deadline))
+    }
+
+    /// バイト列を途切れずにすべて書き込みます。`deadline`がNoneの場合は書き込めるまで待ち続けます。
+    ///
+    /// 他のスレッドの送信が終わるのを待つ間も、送信バッファが空くのを待つ間も期限を適用します。
+    /// 期限を過ぎた場合は[`TimedOut`](io::ErrorKind::TimedOut)エラーを返します。
+    /// その場合はフレームの途中まで送信している可能性があるため、呼び出し元は接続を閉じる必要があります。
+    pub(crate) fn write_bytes_until(&self, mut bytes: &[u8], deadline: Option<Instant>) -> Result<()> {
+        let _frame = self.lock_writer(deadline)?;
+        while !bytes.is_empty() {
+            match self.with_stream(|mut stream| stream.write(bytes)) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => bytes = &bytes[n..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.wait_stream(Interest::Write, remaining_until(deadline)?)?
+                }
+                Err(e) => {
+                    if let Some(reason) = DisconnectReason::from_error(&e) {
+                        self.lock_state().disconnected.get_or_insert(reason);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// フレームの送信を直列化するロックを、`deadline`まで待って取得します。
+    ///
+    /// 期限がある場合は、他のスレッドの送信が終わるのを短い間隔で確認し直します。
+    fn lock_writer(&self, deadline: Option<Instant>) -> Result<MutexGuard<'_, ()>> {
+        if deadline.is_none() {
+            return Ok(self
+                .shared
+                .write_lock
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()));
+        }
+        loop {
+            match self.shared.write_lock.try_lock() {
+                Ok(guard) => return Ok(guard),
+                Err(TryLockError::Poisoned(poisoned)) => return Ok(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) => {}
+            }
+            if let Some(remaining) = remaining_until(deadline)? {
+                std::thread::sleep(remaining.min(WRITE_LOCK_RETRY));
+            }
+        }
+    }
+
+    /// ストリームに対するシステムコールを、他のスレッドと重ならないように実行します。
+    ///
+    /// ストリームは初回の呼び出し時に非ブロッキングモードに切り替えられ、以降はそのまま使用されます。
+    fn with_stream<R>(&self, f: impl FnOnce(&LocalSocketStream) -> Result<R>) -> Result<R> {
+        let _io = self
+            .shared
+            .io_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !self.shared.nonblocking.load(Ordering::Relaxed) {
+            self.shared.stream.set_nonblocking(true)?;
+            self.shared.nonblocking.store(true, Ordering::Relaxed);
+        }
+        f(&self.shared.stream)
+    }
+
+    /// 受信側の状態をロックします。
+    fn lock_state(&self) -> MutexGuard<'_, ReadState> {
+        self.shared.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// ストリームが指定された操作を行えるようになるか、`timeout`が経過するまで待ちます（Unix用）。
+    #[cfg(unix)]
+    fn wait_stream(&self, interest: Interest, timeout: Option<Duration>) -> Result<()> {
+        let mut fds = [self.pollfd(interest)];
+        readiness::poll(&mut fds, timeout).map(|_| ())
+    }
+
+    /// ストリームが指定された操作を行えるようになるか、`timeout`が経過するまで待ちます（非Unix用）。
+    ///
+    /// レディネス通知を利用できないため、一定時間待ってから呼び出し元に再確認させます。
+    #[cfg(not(unix))]
+    fn wait_stream(&self, _interest: Interest, timeout: Option<Duration>) -> Result<()> {
+        readiness::sleep(timeout);
+        Ok(())
+    }
+
+    /// ストリームを待機対象とする`pollfd`を作成します（Unix用）。
+    #[cfg(unix)]
+    pub(crate) fn pollfd(&self, interest: Interest) -> libc::pollfd {
+        use std::os::fd::AsFd;
+
+        let LocalSocketStream::UdSocket(stream) = &self.shared.stream;
+        readiness::pollfd(stream.as_fd(), interest)
+    }
+
+    /// 相手が自身と同じユーザーのプロセスであることを確認します（Unix用）。
+    #[cfg(unix)]
+    fn verify_peer(&self) -> Result<()> {
+        use std::os::fd::AsFd;
+
+        let LocalSocketStream::UdSocket(stream) = &self.shared.stream;
+        credentials::verify_same_user(stream.as_fd())
+    }
+
+    /// 相手の資格情報を取得できないため、検証できないものとしてエラーを返します（非Unix用）。
+    #[cfg(not(unix))]
+    fn verify_peer(&self) -> Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Peer credentials are not available on this platform",
+        ))
+    }
+
+    /// ストリームの送受信を停止します（Unix用）。
+    #[cfg(unix)]
+    fn shutdown_stream(&self) {
+        use std::os::fd::{AsFd, AsRawFd};
+
+        let LocalSocketStream::UdSocket(stream) = &self.shared.stream;
+        // SAFETY: 有効なファイルディスクリプタに対するshutdownはメモリ安全性に影響しません。
+        // 既に切断済みの場合のエラーは無視します。
+        unsafe { libc::shutdown(stream.as_fd().as_raw_fd(), libc::SHUT_RDWR) };
+    }
+
+    /// ストリームの送受信を停止します（非Unix用）。ドロップ時に閉じられるため何もしません。
+    #[cfg(not(unix))]
+    fn shutdown_stream(&self) {}
 }
 
-/// プラットフォームに応じたソケット名を生成します（Windows用）。
-#[cfg(target_os = "windows")]
-fn socket_name(name: &str) -> String {
-    format!(r"\\.\pipe\{}", name)
+/// `deadline`までの残り時間を返します。期限がない場合はNoneを返します。
+///
+/// # エラー
+/// 期限を過ぎている場合は[`TimedOut`](io::ErrorKind::TimedOut)エラーを返します。
+fn remaining_until(deadline: Option<Instant>) -> Result<Option<Duration>> {
+    let Some(deadline) = deadline else {
+        return Ok(None);
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "Frame could not be written in time"));
+    }
+    Ok(Some(remaining))
 }
 
-/// プラットフォームに応じたソケット名を生成します（非Windows用）。
-#[cfg(not(target_os = "windows"))]
-fn socket_name(name: &str) -> String {
-    format!("/tmp/{}", name)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::OversizePolicy;
+    use crate::Server;
+    use std::thread;
+
+    /// テストごとに異なるサーバー名を作成します。
+    fn test_name(test: &str) -> String {
+        format!("instance-pipe-test-{}-{}", test, std::process::id())
+    }
+
+    /// サーバーとクライアントを接続し、サーバー側の接続とクライアントを返します。
+    fn connect(test: &str, config: ProtocolConfig) -> (Server, Client, Client) {
+        let name = test_name(test);
+        let mut server = Server::start(&name).unwrap();
+        let connecting = thread::spawn(move || Client::start_with(&name, config));
+        let peer = server.accept().unwrap();
+        let client = connecting.join().unwrap().unwrap();
+        (server, peer, client)
+    }
+
+    #[test]
+    fn frames_after_skipped_oversize_frame_are_delivered() {
+        let config = ProtocolConfig::new()
+            .max_frame_size(16)
+            .oversize_policy(OversizePolicy::Skip);
+        let (_server, peer, mut client) = connect("skip", config);
+
+        // 最大サイズを超えるフレームと通常のフレームを、1回の読み込みで届くようにまとめて送信する
+        let mut bytes = 33u32.to_le_bytes().to_vec();
+        bytes.push(FrameKind::Data as u8);
+        bytes.extend([0u8; 32]);
+        let message = peer.get_config().get_codec().encode(&7u32).unwrap();
+        bytes.extend(protocol::encode_frame(&Frame::data(message), peer.get_config()).unwrap());
+        peer.write_bytes(&bytes).unwrap();
+
+        client.set_timeout(Duration::from_secs(5));
+        assert!(matches!(client.poll_event::<u32>().unwrap(), Some(Event::ProtocolError(_))));
+        assert!(matches!(client.poll_event::<u32>().unwrap(), Some(Event::MessageReceived(7))));
+    }
+
+    /// 相関IDだけを持つRPCリクエストのフレームを作成します。
+    fn request(id: u64) -> Frame {
+        Frame {
+            kind: FrameKind::Request,
+            payload: id.to_le_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn unconsumed_requests_are_capped() {
+        let (_server, peer, mut client) = connect("queue-full", ProtocolConfig::new());
+        // 小さな書き込みを繰り返すと送信バッファを使い切るため、1回にまとめて送信する
+        let mut bytes = Vec::new();
+        for id in 0..=MAX_QUEUED_FRAMES as u64 {
+            bytes.extend(protocol::encode_frame(&request(id), peer.get_config()).unwrap());
+        }
+        peer.write_bytes(&bytes).unwrap();
+        peer.send(&7u32).unwrap();
+
+        client.set_timeout(Duration::from_secs(5));
+        match client.poll_event::<u32>().unwrap() {
+            Some(Event::ProtocolError(e)) => assert_eq!(e.kind(), protocol::ProtocolErrorKind::QueueFull),
+            _ => panic!("expected the excess request to be reported"),
+        }
+        assert!(matches!(client.poll_event::<u32>().unwrap(), Some(Event::MessageReceived(7))));
+        assert_eq!(client.lock_state().inbox.len(), MAX_QUEUED_FRAMES);
+    }
+
+    #[test]
+    fn frames_for_capabilities_not_negotiated_are_rejected() {
+        let (_server, peer, mut client) = connect("not-negotiated", ProtocolConfig::new());
+        client.negotiated.capabilities = Capabilities::GOODBYE;
+        peer.write_frame(&request(1)).unwrap();
+        peer.send(&7u32).unwrap();
+
+        client.set_timeout(Duration::from_secs(5));
+        match client.poll_event::<u32>().unwrap() {
+            Some(Event::ProtocolError(e)) => assert_eq!(e.kind(), protocol::ProtocolErrorKind::InvalidFrame),
+            _ => panic!("expected the request to be rejected"),
+        }
+        assert!(matches!(client.poll_event::<u32>().unwrap(), Some(Event::MessageReceived(7))));
+        assert!(client.lock_state().inbox.is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::protocol::{ProtocolError, ProtocolErrorKind};
+use std::io::{self, Result};
+
+/// 接続の相手が自身と同じ実効ユーザーのプロセスであることを確認します。
+///
+/// # エラー
+/// 相手の資格情報の取得に失敗した場合や、相手のユーザーが異なる場合にエラーを返します。
+/// ユーザーが異なる場合のエラーは[`ProtocolErrorKind::UnauthorizedPeer`]のプロトコルエラーです。
+pub(crate) fn verify_same_user(fd: std::os::fd::BorrowedFd<'_>) -> Result<()> {
+    let peer = peer_uid(fd)?;
+    // SAFETY: geteuidは常に成功し、副作用を持ちません。
+    let local = unsafe { libc::geteuid() };
+    if peer != local {
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::UnauthorizedPeer,
+            format!("Peer is running as user {}, expected user {}", peer, local),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// 接続の相手の実効ユーザーIDを取得します（Linux・Android用）。
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(fd: std::os::fd::BorrowedFd<'_>) -> Result<libc::uid_t> {
+    use std::os::fd::AsRawFd;
+
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: credentialsとlenは呼び出しの間有効で、lenはcredentialsのサイズを示しています。
+    let result = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut credentials as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+/// 接続の相手の実効ユーザーIDを取得します（macOS・BSD用）。
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+fn peer_uid(fd: std::os::fd::BorrowedFd<'_>) -> Result<libc::uid_t> {
+    use std::os::fd::AsRawFd;
+
+    let mut uid = 0;
+    let mut gid = 0;
+    // SAFETY: uidとgidは呼び出しの間有効です。
+    if unsafe { libc::getpeereid(fd.as_raw_fd(), &mut uid, &mut gid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// 相手の資格情報を取得できないため、検証できないものとしてエラーを返します（その他のUnix用）。
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+fn peer_uid(_fd: std::os::fd::BorrowedFd<'_>) -> Result<libc::uid_t> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Peer credentials are not available on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn same_user_peer_is_accepted() {
+        let (local, peer) = UnixStream::pair().unwrap();
+        verify_same_user(local.as_fd()).unwrap();
+        verify_same_user(peer.as_fd()).unwrap();
+    }
+}
@@ -1,15 +1,143 @@
-use std::sync::{Arc, Mutex};
+use super::client::Client;
+use super::server::ConnectionId;
+use crate::protocol::ProtocolError;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
 
+/// 接続で発生したイベント。
+///
+/// 今後のバージョンでバリアントが追加される可能性があるため、`match`ではワイルドカードの腕が必要です。
 #[derive(Clone)]
+#[non_exhaustive]
 pub enum Event<T> {
-    ConnectionAccepted(super::client::Client),
+    /// サーバーが新しい接続を受け入れました。
+    ConnectionAccepted(Client),
+    /// メッセージを送信しました。
     MessageSent,
+    /// メッセージを受信しました。
     MessageReceived(T),
+    /// 接続が切断されました。
+    ///
+    /// `connection`はサーバーが受け入れた接続の場合にその接続IDを示します。
+    Disconnected {
+        connection: Option<ConnectionId>,
+        reason: DisconnectReason,
+    },
+    /// 相手がプロトコルに違反したフレームを送信しました。
+    ProtocolError(ProtocolError),
+    /// 相手がgoodbyeフレームで接続の終了を通知しました。
+    ///
+    /// 相手は処理中のやり取りを終えてから接続を閉じます。新しいやり取りは始めずに、
+    /// [`Client::stop`]で接続を閉じてください。
+    Goodbye { connection: Option<ConnectionId> },
 }
 
+impl<T> Event<T> {
+    /// 受信時のエラーを、対応するイベントに変換します。
+    ///
+    /// 切断を示すエラーは[`Event::Disconnected`]、プロトコルエラーは[`Event::ProtocolError`]となり、
+    /// それ以外のエラーはそのまま返されます。
+    pub(crate) fn from_error(error: io::Error, connection: Option<ConnectionId>) -> io::Result<Self> {
+        if let Some(reason) = DisconnectReason::from_error(&error) {
+            return Ok(Event::Disconnected { connection, reason });
+        }
+        match ProtocolError::from_io(&error) {
+            Some(protocol_error) => Ok(Event::ProtocolError(protocol_error)),
+            None => Err(error),
+        }
+    }
+}
+
+/// 接続が切断された理由。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// 相手が接続を閉じました。
+    Closed,
+    /// 接続が異常終了しました（broken pipe、connection resetなど）。
+    Reset,
+    /// 接続が終了処理により閉じられました。
+    ///
+    /// 相手がgoodbyeフレームで終了を通知してから接続を閉じた場合と、こちらが[`Client::stop`]で接続を閉じた場合を示します。
+    Shutdown,
+}
+
+impl DisconnectReason {
+    /// I/Oエラーが切断を示している場合に、その理由を返します。
+    pub fn from_error(error: &io::Error) -> Option<Self> {
+        if let Some(reason) = error.get_ref().and_then(|inner| inner.downcast_ref::<Self>()) {
+            return Some(*reason);
+        }
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => Some(Self::Closed),
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected => Some(Self::Reset),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => f.write_str("peer closed the connection"),
+            Self::Reset => f.write_str("connection was reset"),
+            Self::Shutdown => f.write_str("connection was shut down"),
+        }
+    }
+}
+
+impl std::error::Error for DisconnectReason {}
+
+impl From<DisconnectReason> for io::Error {
+    fn from(reason: DisconnectReason) -> Self {
+        let kind = match reason {
+            DisconnectReason::Closed | DisconnectReason::Shutdown => io::ErrorKind::UnexpectedEof,
+            DisconnectReason::Reset => io::ErrorKind::ConnectionReset,
+        };
+        io::Error::new(kind, reason)
+    }
+}
+
+/// メッセージリスナー。登録時の型と一致するメッセージに対してのみ呼び出されます。
+type MessageListener = Arc<dyn Fn(&dyn Any) + Send + Sync>;
+/// 接続リスナー。
+type ConnectListener = Arc<dyn Fn(&Client) + Send + Sync>;
+/// 切断リスナー。
+type DisconnectListener = Arc<dyn Fn(Option<ConnectionId>, DisconnectReason) + Send + Sync>;
+/// 送信リスナー。
+type SentListener = Arc<dyn Fn() + Send + Sync>;
+
+/// イベントを登録済みのリスナーに通知するハンドラー。
+///
+/// クローンしたハンドラーはリスナーを共有します。サーバーのハンドラーは、そのサーバーが受け入れた
+/// すべての接続に引き継がれるため、サーバーに登録したリスナーは各接続のイベントでも呼び出されます。
+///
+/// 既定ではイベントの記録を行いません。デバッグ用にイベント名を記録する場合は
+/// [`with_log`](EventHandler::with_log)で上限付きのログを有効にします。
 #[derive(Clone)]
 pub struct EventHandler {
-    events: Arc<Mutex<Vec<String>>>,
+    listeners: Arc<Mutex<Listeners>>,
+    log: Arc<Mutex<Option<EventLog>>>,
+}
+
+/// 登録済みのリスナー。
+#[derive(Default)]
+struct Listeners {
+    message: Vec<MessageListener>,
+    connect: Vec<ConnectListener>,
+    disconnect: Vec<DisconnectListener>,
+    sent: Vec<SentListener>,
+}
+
+/// 直近のイベント名を上限付きで保持するリングバッファ。
+struct EventLog {
+    events: VecDeque<String>,
+    capacity: usize,
 }
 
 impl Default for EventHandler {
@@ -19,24 +147,125 @@ impl Default for EventHandler {
 }
 
 impl EventHandler {
+    /// リスナーを持たず、ログも記録しないハンドラーを作成します。
     pub fn new() -> Self {
         Self {
-            events: Arc::new(Mutex::new(Vec::new())),
+            listeners: Arc::new(Mutex::new(Listeners::default())),
+            log: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub fn notify<T>(&self, event: Event<T>) {
-        let event_str = match event {
-            Event::ConnectionAccepted(_) => "ConnectionAccepted".to_string(),
-            Event::MessageSent => "MessageSent".to_string(),
-            Event::MessageReceived(_) => "MessageReceived".to_string(),
-        };
-        if let Ok(mut events) = self.events.lock() {
-            events.push(event_str);
+    /// 直近`capacity`件のイベント名を記録するハンドラーを作成します。
+    ///
+    /// 上限を超えると古いイベントから破棄されます。
+    pub fn with_log(capacity: usize) -> Self {
+        let handler = Self::new();
+        handler.enable_log(capacity);
+        handler
+    }
+
+    /// 直近`capacity`件のイベント名の記録を開始します。
+    ///
+    /// [`Client::event_handler`]や[`Server::event_handler`](crate::Server::event_handler)で取得した
+    /// ハンドラーに対して呼び出すと、同じハンドラーを共有する接続すべてのイベントが記録されます。
+    /// すでに記録している場合は、記録済みのイベントを破棄して上限を変更します。
+    pub fn enable_log(&self, capacity: usize) {
+        *self.lock_log() = Some(EventLog {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        });
+    }
+
+    /// `T`型のメッセージを受信したときに呼び出されるリスナーを登録します。
+    ///
+    /// 異なる型として受信したメッセージでは呼び出されません。
+    pub fn on_message<T: 'static>(&self, listener: impl Fn(&T) + Send + Sync + 'static) {
+        self.lock_listeners().message.push(Arc::new(move |message: &dyn Any| {
+            if let Some(message) = message.downcast_ref::<T>() {
+                listener(message);
+            }
+        }));
+    }
+
+    /// 接続を受け入れたときに呼び出されるリスナーを登録します。
+    pub fn on_connect(&self, listener: impl Fn(&Client) + Send + Sync + 'static) {
+        self.lock_listeners().connect.push(Arc::new(listener));
+    }
+
+    /// 接続の切断を検出したときに呼び出されるリスナーを登録します。
+    ///
+    /// 1つの接続につき1回だけ呼び出されます。
+    pub fn on_disconnect(&self, listener: impl Fn(Option<ConnectionId>, DisconnectReason) + Send + Sync + 'static) {
+        self.lock_listeners().disconnect.push(Arc::new(listener));
+    }
+
+    /// メッセージを送信したときに呼び出されるリスナーを登録します。
+    pub fn on_sent(&self, listener: impl Fn() + Send + Sync + 'static) {
+        self.lock_listeners().sent.push(Arc::new(listener));
+    }
+
+    /// イベントを登録済みのリスナーに通知し、ログが有効であれば記録します。
+    ///
+    /// リスナーはハンドラーのロックを解放してから呼び出されるため、リスナー内で新たなリスナーを登録できます。
+    pub fn notify<T: 'static>(&self, event: &Event<T>) {
+        self.record(match event {
+            Event::ConnectionAccepted(_) => "ConnectionAccepted",
+            Event::MessageSent => "MessageSent",
+            Event::MessageReceived(_) => "MessageReceived",
+            Event::Disconnected { .. } => "Disconnected",
+            Event::ProtocolError(_) => "ProtocolError",
+            Event::Goodbye { .. } => "Goodbye",
+        });
+
+        match event {
+            Event::ConnectionAccepted(client) => {
+                let listeners = self.lock_listeners().connect.clone();
+                listeners.iter().for_each(|listener| listener(client));
+            }
+            Event::MessageSent => {
+                let listeners = self.lock_listeners().sent.clone();
+                listeners.iter().for_each(|listener| listener());
+            }
+            Event::MessageReceived(message) => {
+                let listeners = self.lock_listeners().message.clone();
+                listeners.iter().for_each(|listener| listener(message));
+            }
+            Event::Disconnected { connection, reason } => {
+                let listeners = self.lock_listeners().disconnect.clone();
+                listeners.iter().for_each(|listener| listener(*connection, *reason));
+            }
+            Event::ProtocolError(_) | Event::Goodbye { .. } => {}
+        }
+    }
+
+    /// ログが有効であれば、イベント名を記録します。
+    ///
+    /// [`Event`]以外のイベントを通知する型から、そのイベント名を記録するために使用します。
+    pub(crate) fn record(&self, name: &str) {
+        if let Some(log) = self.lock_log().as_mut()
+            && log.capacity > 0
+        {
+            if log.events.len() == log.capacity {
+                log.events.pop_front();
+            }
+            log.events.push_back(name.to_string());
         }
     }
 
+    /// 記録済みのイベント名を古い順に返します。ログが無効な場合は空です。
     pub fn get_events(&self) -> Vec<String> {
-        self.events.lock().map_or(Vec::new(), |events| events.clone())
+        self.lock_log()
+            .as_ref()
+            .map_or(Vec::new(), |log| log.events.iter().cloned().collect())
+    }
+
+    /// イベントのログをロックします。
+    fn lock_log(&self) -> MutexGuard<'_, Option<EventLog>> {
+        self.log.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
     }
-}
\ No newline at end of file
+
+    /// 登録済みのリスナーをロックします。
+    fn lock_listeners(&self) -> MutexGuard<'_, Listeners> {
+        self.listeners.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,313 @@
+use crate::instance::event::{DisconnectReason, Event};
+use crate::instance::rpc::RpcRequest;
+use crate::instance::readiness::Interest;
+use crate::instance::server::ConnectionId;
+use crate::protocol::codec::Codec;
+use crate::protocol::FrameKind;
+use crate::{Client, Server};
+use serde::de::DeserializeOwned;
+use std::io::{self, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use crate::instance::readiness;
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+
+/// 終了の要求で起床できない場合に、次に終了の要求を確認するまでの待機時間。
+const RUN_IDLE_DELAY: Duration = Duration::from_millis(10);
+
+/// [`Server::run`]から呼び出される、接続ごとのイベントのハンドラー。
+///
+/// すべてのメソッドは`run`を呼び出したスレッドで順に呼び出されます。
+pub trait ConnectionHandler {
+    /// クライアントから受信するメッセージの型。
+    type Message: DeserializeOwned + 'static;
+
+    /// 接続を受け入れ、ハンドシェイクが完了したときに呼び出されます。
+    fn on_connect(&mut self, _client: &Client) {}
+
+    /// クライアントからメッセージを受信したときに呼び出されます。
+    ///
+    /// 応答は`client`の[`send`](Client::send)で送信できます。
+    fn on_message(&mut self, client: &Client, message: Self::Message);
+
+    /// クライアントからRPCリクエストを受信したときに呼び出されます。
+    ///
+    /// リクエストは[`Message`](ConnectionHandler::Message)型としてデコードされます。
+    /// 応答は`request`の[`respond`](RpcRequest::respond)または[`respond_err`](RpcRequest::respond_err)で返します。
+    /// 既定では、呼び出し元がタイムアウトまで待たないよう、リクエストを処理しないことを示すエラーを返します。
+    fn on_request(&mut self, _client: &Client, request: RpcRequest<Self::Message>) {
+        // 既に切断済みの場合のエラーは無視する
+        let _ = request.respond_err("This server does not handle requests");
+    }
+
+    /// クライアントがgoodbyeフレームで終了を通知したときに呼び出されます。
+    ///
+    /// 相手は処理中のやり取りを終えてから接続を閉じるため、続いて[`on_disconnect`](ConnectionHandler::on_disconnect)が呼び出されます。
+    fn on_goodbye(&mut self, _client: &Client) {}
+
+    /// 接続が切断されたときに呼び出されます。
+    fn on_disconnect(&mut self, _connection: ConnectionId, _reason: DisconnectReason) {}
+
+    /// メッセージのデコードに失敗した場合や、プロトコル違反を検出した場合に呼び出されます。
+    ///
+    /// 既定では何もしません。エラーはその接続のみに影響し、他の接続の処理は継続されます。
+    fn on_error(&mut self, _client: &Client, _error: io::Error) {}
+}
+
+/// [`Server::run`]を終了させるためのハンドル。
+///
+/// クローンしたハンドルは同じ状態を共有するため、別のスレッドやシグナルハンドラーに渡して使用できます。
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+/// クローン間で共有される終了要求の状態。
+#[derive(Debug)]
+struct ShutdownState {
+    triggered: AtomicBool,
+    /// 待機中の`run`を起床させるためのパイプ（読み込み側, 書き込み側）。
+    #[cfg(unix)]
+    waker: Option<(OwnedFd, OwnedFd)>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownHandle {
+    /// 新しいハンドルを作成します。
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(ShutdownState {
+                triggered: AtomicBool::new(false),
+                // パイプを作成できない場合は、runが定期的に終了要求を確認する
+                #[cfg(unix)]
+                waker: waker_pipe().ok(),
+            }),
+        }
+    }
+
+    /// サーバーに終了を要求します。
+    ///
+    /// 実行中の[`Server::run`]は、処理中のイベントを終えた後に戻ります。
+    pub fn shutdown(&self) {
+        self.state.triggered.store(true, Ordering::Release);
+        #[cfg(unix)]
+        if let Some((_, writer)) = &self.state.waker {
+            // SAFETY: 有効なファイルディスクリプタに1バイト書き込むのみです。
+            // パイプが満杯の場合も既に起床可能な状態のため、結果は無視します。
+            unsafe { libc::write(writer.as_raw_fd(), [1u8].as_ptr().cast(), 1) };
+        }
+    }
+
+    /// 終了が要求されているかどうかを返します。
+    pub fn is_shutdown(&self) -> bool {
+        self.state.triggered.load(Ordering::Acquire)
+    }
+
+    /// 終了の要求で起床できるかどうかを返します。
+    fn can_wake(&self) -> bool {
+        #[cfg(unix)]
+        return self.state.waker.is_some();
+        #[cfg(not(unix))]
+        return false;
+    }
+
+    /// 終了の要求を待機対象とする`pollfd`を作成します（Unix用）。
+    #[cfg(unix)]
+    pub(crate) fn pollfd(&self) -> Option<libc::pollfd> {
+        let (reader, _) = self.state.waker.as_ref()?;
+        Some(readiness::pollfd(reader.as_fd(), Interest::Read))
+    }
+}
+
+/// 非ブロッキングかつexec時に閉じられるパイプを作成します（Unix用）。
+#[cfg(unix)]
+fn waker_pipe() -> Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds`は2要素の配列で、成功時にはpipeが有効なファイルディスクリプタを書き込みます。
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipeが返したファイルディスクリプタは、ここで初めて所有されます。
+    let pipe = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    for fd in [fds[0], fds[1]] {
+        // SAFETY: 有効なファイルディスクリプタのフラグを変更するのみです。
+        unsafe {
+            if libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK) < 0
+                || libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(pipe)
+}
+
+impl Server {
+    /// 接続の受け入れと各接続からの受信を行い、イベントを`handler`に渡し続けます。
+    ///
+    /// 各接続に届いたメッセージ・RPCリクエスト・goodbyeは、この順に
+    /// [`on_message`](ConnectionHandler::on_message)・[`on_request`](ConnectionHandler::on_request)・
+    /// [`on_goodbye`](ConnectionHandler::on_goodbye)に渡されます。
+    ///
+    /// [`shutdown_handle`](Server::shutdown_handle)で取得したハンドルから終了が要求されると戻ります。
+    /// 終了が要求済みの場合は、すぐに戻ります。
+    ///
+    /// # 引数
+    /// - `handler`: 接続・メッセージ・切断を処理するハンドラー。
+    ///
+    /// # エラー
+    /// リスナーでの接続の受け入れに失敗した場合にエラーを返します。
+    /// 個々の接続で発生したエラーは[`ConnectionHandler::on_error`]に渡され、`run`は継続します。
+    /// ハンドシェイクに失敗した接続は登録されずに閉じられ、ハンドラーには通知されません。
+    pub fn run<H: ConnectionHandler>(&mut self, handler: &mut H) -> Result<()> {
+        let shutdown = self.shutdown_handle();
+        // 切断の検出時には登録が解除されているため、残りのフレームを渡せるよう接続を保持しておく
+        let mut clients: BTreeMap<ConnectionId, Client> = self
+            .connections()
+            .into_iter()
+            .filter_map(|client| Some((client.get_connection_id()?, client)))
+            .collect();
+        while !shutdown.is_shutdown() {
+            let mut idle = true;
+            while let Some(event) = self.next_event(Duration::ZERO)? {
+                idle = false;
+                match event {
+                    Event::ConnectionAccepted(client) => {
+                        handler.on_connect(&client);
+                        if let Some(connection) = client.get_connection_id() {
+                            clients.insert(connection, client);
+                        }
+                    }
+                    Event::Disconnected {
+                        connection: Some(connection),
+                        reason,
+                    } => {
+                        // 切断の直前に届いていたフレームを渡してから切断を通知する
+                        if let Some(client) = clients.remove(&connection) {
+                            dispatch_to_handler(&client, handler);
+                        }
+                        handler.on_disconnect(connection, reason);
+                    }
+                    // ハンドシェイクに失敗した接続は登録されないため、ハンドラーには通知しない
+                    _ => {}
+                }
+            }
+
+            for client in clients.values() {
+                if dispatch_to_handler(client, handler) {
+                    idle = false;
+                }
+            }
+
+            if idle {
+                // 終了の要求で起床できない場合は、定期的に起床して確認する
+                let timeout = (!shutdown.can_wake()).then_some(RUN_IDLE_DELAY);
+                self.wait_ready(Interest::Read, Some(&shutdown), timeout)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 接続に届いているメッセージ・RPCリクエスト・goodbyeを、届いている分だけ順にハンドラーに渡します。
+///
+/// 1つでも渡した場合にtrueを返します。
+fn dispatch_to_handler<H: ConnectionHandler>(client: &Client, handler: &mut H) -> bool {
+    let mut dispatched = false;
+    for kind in [FrameKind::Data, FrameKind::Request, FrameKind::Goodbye] {
+        loop {
+            let frame = match client.try_next_frame(kind) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    // 切断は次回の接続の確認で検出され、on_disconnectとして通知される
+                    if DisconnectReason::from_error(&e).is_none() {
+                        handler.on_error(client, e);
+                    }
+                    // 他の種類のフレームは切断前に届いている場合があるため、続けて確認する
+                    break;
+                }
+            };
+            dispatched = true;
+            match kind {
+                FrameKind::Data => match client.get_config().get_codec().decode::<H::Message>(&frame.payload) {
+                    Ok(message) => {
+                        let event = Event::MessageReceived(message);
+                        client.event_handler().notify(&event);
+                        if let Event::MessageReceived(message) = event {
+                            handler.on_message(client, message);
+                        }
+                    }
+                    Err(e) => handler.on_error(client, e),
+                },
+                FrameKind::Request => match client.decode_request(frame) {
+                    Ok(request) => handler.on_request(client, request),
+                    Err(e) => handler.on_error(client, e),
+                },
+                _ => handler.on_goodbye(client),
+            }
+        }
+    }
+    dispatched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Sender};
+    use std::thread;
+
+    /// 受け取ったイベントを名前で記録し、リクエストには1を足して応答するハンドラー。
+    struct Recorder {
+        events: Sender<&'static str>,
+    }
+
+    impl ConnectionHandler for Recorder {
+        type Message = u32;
+
+        fn on_message(&mut self, _client: &Client, _message: u32) {
+            self.events.send("message").unwrap();
+        }
+
+        fn on_request(&mut self, _client: &Client, request: RpcRequest<u32>) {
+            self.events.send("request").unwrap();
+            request.respond(&(request.request() + 1)).unwrap();
+        }
+
+        fn on_goodbye(&mut self, _client: &Client) {
+            self.events.send("goodbye").unwrap();
+        }
+
+        fn on_disconnect(&mut self, _connection: ConnectionId, _reason: DisconnectReason) {
+            self.events.send("disconnect").unwrap();
+        }
+    }
+
+    #[test]
+    fn run_answers_requests_and_reports_goodbye() {
+        let name = format!("instance-pipe-test-run-{}", std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let shutdown = server.shutdown_handle();
+        let (sender, events) = mpsc::channel();
+        let running = thread::spawn(move || server.run(&mut Recorder { events: sender }));
+
+        let mut client = Client::connect_timeout(&name, Duration::from_secs(5)).unwrap();
+        client.send(&1u32).unwrap();
+        assert_eq!(client.call::<u32, u32>(&20, Duration::from_secs(5)).unwrap(), 21);
+        client.stop().unwrap();
+
+        let received: Vec<_> = (0..4).map(|_| events.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(received, ["message", "request", "goodbye", "disconnect"]);
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+}
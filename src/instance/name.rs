@@ -0,0 +1,459 @@
+use interprocess::local_socket::{GenericNamespaced, ListenerOptions, Name, NameType, ToFsName, ToNsName};
+use interprocess::os::unix::local_socket::FilesystemUdSocket;
+use sha2::{Digest, Sha256};
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{self, Result};
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// 名前の先頭に残すアプリケーションIDの最大長。
+const PREFIX_MAX_LEN: usize = 32;
+/// 名前に含めるハッシュのバイト数。16進数表記ではこの2倍の長さになります。
+const DIGEST_LEN: usize = 16;
+
+/// アプリケーションIDから衝突しないインスタンス名を導出するビルダー。
+///
+/// アプリケーションIDにユーザーID・セッション・作業ディレクトリを組み合わせてSHA-256でハッシュし、
+/// 長さが制限されたファイルシステム上で安全な名前を生成します。
+/// 生成した名前は[`Server::start`](crate::Server::start)や[`Client::start`](crate::Client::start)に
+/// そのまま渡すことができます。
+///
+/// 既定ではユーザーごとに異なる名前を生成し、セッションおよびディレクトリは区別しません。
+///
+/// 名前はユーザーIDを含めても他のユーザーが計算できるため、名前だけでは相手のユーザーを保証できません。
+/// 他のユーザーからの接続を拒否するには、[`ProtocolConfig::require_same_user`](crate::ProtocolConfig::require_same_user)で
+/// 相手の資格情報を検証します。[`SingleInstance`](crate::SingleInstance)はユーザーごとの名前で自動的に検証を行います。
+#[derive(Clone, Debug)]
+pub struct InstanceName {
+    app_id: String,
+    per_user: bool,
+    per_session: bool,
+    scope: Option<PathBuf>,
+}
+
+impl InstanceName {
+    /// 指定されたアプリケーションIDから新しいビルダーを作成します。
+    ///
+    /// # 引数
+    /// - `app_id`: アプリケーションを識別する文字列（例: `"com.example.editor"`）。
+    pub fn new(app_id: &str) -> Self {
+        Self {
+            app_id: app_id.to_string(),
+            per_user: true,
+            per_session: false,
+            scope: None,
+        }
+    }
+
+    /// ユーザーごとに異なる名前を生成するかどうかを設定します。
+    pub fn per_user(mut self, enabled: bool) -> Self {
+        self.per_user = enabled;
+        self
+    }
+
+    /// ユーザーごとに異なる名前を生成するかどうかを取得します。
+    pub fn get_per_user(&self) -> bool {
+        self.per_user
+    }
+
+    /// ログインセッション（シート）ごとに異なる名前を生成するかどうかを設定します。
+    pub fn per_session(mut self, enabled: bool) -> Self {
+        self.per_session = enabled;
+        self
+    }
+
+    /// 指定されたディレクトリごとに異なる名前を生成します。
+    ///
+    /// 同じアプリケーションの別のチェックアウトを区別する場合などに使用します。
+    /// パスは可能であれば正規化されます。
+    ///
+    /// # 引数
+    /// - `dir`: 名前のスコープとするディレクトリ。
+    pub fn scope_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        self.scope = Some(dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()));
+        self
+    }
+
+    /// 設定に基づいてインスタンス名を生成します。
+    ///
+    /// 名前は`<アプリケーションID>-<ハッシュ>`の形式で、英数字・`.`・`_`・`-`のみで構成されます。
+    ///
+    /// # エラー
+    /// ユーザーIDまたはセッションの取得に失敗した場合にエラーを返します。
+    pub fn build(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        update_field(&mut hasher, self.app_id.as_bytes());
+        if self.per_user {
+            update_field(&mut hasher, user_id()?.as_bytes());
+        }
+        if self.per_session {
+            update_field(&mut hasher, session_id()?.as_bytes());
+        }
+        if let Some(scope) = &self.scope {
+            update_field(&mut hasher, scope.to_string_lossy().as_bytes());
+        }
+        let digest = hasher.finalize();
+
+        let mut name: String = self
+            .app_id
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+                _ => '_',
+            })
+            .take(PREFIX_MAX_LEN)
+            .collect();
+        name.push('-');
+        for byte in &digest[..DIGEST_LEN] {
+            name.push_str(&format!("{:02x}", byte));
+        }
+        Ok(name)
+    }
+}
+
+/// 長さプレフィックス付きでハッシュにフィールドを追加します。
+///
+/// フィールドの境界を明確にし、`("ab", "c")`と`("a", "bc")`が衝突しないようにします。
+fn update_field(hasher: &mut Sha256, field: &[u8]) {
+    hasher.update((field.len() as u64).to_le_bytes());
+    hasher.update(field);
+}
+
+/// 現在の実効ユーザーを識別する文字列を返します（非Windows用）。
+///
+/// 接続の相手の検証と同じく実効ユーザーIDを使用し、setuidされたプロセスでも名前と検証するユーザーを一致させます。
+#[cfg(not(target_os = "windows"))]
+fn user_id() -> Result<String> {
+    // SAFETY: geteuidは常に成功し、副作用を持ちません。
+    Ok(unsafe { libc::geteuid() }.to_string())
+}
+
+/// 現在のユーザーを識別する文字列を返します（Windows用）。
+#[cfg(target_os = "windows")]
+fn user_id() -> Result<String> {
+    let user = std::env::var("USERNAME").map_err(io::Error::other)?;
+    let domain = std::env::var("USERDOMAIN").unwrap_or_default();
+    Ok(format!("{}\\{}", domain, user))
+}
+
+/// 現在のログインセッションを識別する文字列を返します（非Windows用）。
+///
+/// `XDG_SESSION_ID`と`XDG_SEAT`を優先し、設定されていない場合はプロセスのセッションIDを使用します。
+#[cfg(not(target_os = "windows"))]
+fn session_id() -> Result<String> {
+    if let Ok(session) = std::env::var("XDG_SESSION_ID") {
+        let seat = std::env::var("XDG_SEAT").unwrap_or_default();
+        return Ok(format!("{}:{}", seat, session));
+    }
+    // SAFETY: getsid(0)は呼び出し元プロセスのセッションIDを返すのみです。
+    let sid = unsafe { libc::getsid(0) };
+    if sid < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sid.to_string())
+}
+
+/// 現在のログインセッションを識別する文字列を返します（Windows用）。
+#[cfg(target_os = "windows")]
+fn session_id() -> Result<String> {
+    std::env::var("SESSIONNAME").map_err(io::Error::other)
+}
+
+/// 名前をプラットフォームに応じたソケット名に変換します。
+///
+/// 名前空間ソケットが利用可能な場合はそれを優先し、利用できない場合はファイルシステムソケットを使用します。
+///
+/// # エラー
+/// 名前が不正な場合や、サポートされていないソケットタイプの場合にエラーを返します。
+pub(crate) fn resolve(name: &str) -> Result<Name<'static>> {
+    let name = socket_name(name);
+    if GenericNamespaced::is_supported() {
+        name.to_ns_name::<GenericNamespaced>()
+    } else if FilesystemUdSocket::is_supported() {
+        name.to_fs_name::<FilesystemUdSocket>()
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Neither namespaced nor filesystem-based sockets are supported",
+        ))
+    }
+}
+
+/// 名前を解決し、その名前で接続の待ち受けを開始します。
+///
+/// ファイルシステムソケットのファイルが既に存在して作成に失敗した場合は、接続を試みて待ち受けているサーバーがあるかを確認します。
+/// 誰も待ち受けていなければ、クラッシュしたサーバーが残したファイルとみなし、
+/// `reclaim`が有効であれば削除してから作成し直します。名前空間ソケットはプロセスの終了とともに解放されるため、確認は行いません。
+///
+/// ファイルシステムソケットの確認・削除・作成は、ロックファイルに対する排他的な`flock`を保持したまま行います。
+/// 返されたリスナーのドロップ時にも同じロックを取得してからソケットファイルを削除するため、
+/// 同時に起動したサーバーや終了中のサーバーが、作成されたばかりのソケットファイルを削除することはありません。
+///
+/// ロックファイルは共有の`/tmp`ではなく、ユーザーごとのディレクトリ（[`lock_dir`]）に作成して削除せずに残します。
+/// 他のユーザーがロックファイルを先に作成して名前を使えなくすることはできません。
+/// 異なるユーザーのサーバー同士は直列化されませんが、スティッキービットにより
+/// 他のユーザーのソケットファイルを削除することはできないため、競合は起こりません。
+///
+/// # 引数
+/// - `name`: パイプまたはソケットの名前。
+/// - `reclaim`: 残存したソケットファイルを削除するかどうか。
+/// - `create`: 設定からリスナーを作成する関数。
+///
+/// # エラー
+/// 他のサーバーが待ち受けている場合や、残存したファイルを削除しない場合、
+/// 既存のファイルがソケットでない場合、ロックの取得やリスナーの作成に失敗した場合にエラーを返します。
+pub(crate) fn listen<L>(
+    name: &str,
+    reclaim: bool,
+    create: impl Fn(ListenerOptions<'static>) -> Result<L>,
+) -> Result<Listening<L>> {
+    let resolved = resolve(name)?;
+    if !resolved.is_path() {
+        return Ok(Listening {
+            listener: Some(create(ListenerOptions::new().name(resolved))?),
+            lock_path: None,
+        });
+    }
+
+    let path = socket_name(name);
+    let file_name = Path::new(&path).file_name().unwrap_or_default().to_string_lossy();
+    let lock_path = lock_dir()?.join(format!("{}.lock", file_name));
+    let _lock = lock(&lock_path)?;
+    let listener = match create(ListenerOptions::new().name(resolved)) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if !is_stale(Path::new(&path))? {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("Another server is listening on {}", path),
+                ));
+            }
+            if !reclaim {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("Stale socket file {} was left by a previous server", path),
+                ));
+            }
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            create(ListenerOptions::new().name(resolve(name)?))?
+        }
+        result => result?,
+    };
+    Ok(Listening {
+        listener: Some(listener),
+        lock_path: Some(lock_path),
+    })
+}
+
+/// [`listen`]で作成したリスナー。
+///
+/// ファイルシステムソケットの場合は、ドロップ時にロックを取得してからリスナーを閉じ、ソケットファイルを削除します。
+pub(crate) struct Listening<L> {
+    /// 待ち受けているリスナー。ドロップ時以外は常にSome。
+    listener: Option<L>,
+    /// ファイルシステムソケットのロックファイルのパス。名前空間ソケットではNone。
+    lock_path: Option<PathBuf>,
+}
+
+impl<L> Deref for Listening<L> {
+    type Target = L;
+
+    fn deref(&self) -> &L {
+        self.listener.as_ref().expect("listener is present until dropped")
+    }
+}
+
+impl<L> Drop for Listening<L> {
+    fn drop(&mut self) {
+        // ロックを取得できなくてもリスナーは閉じる
+        let _lock = self.lock_path.as_deref().map(lock);
+        self.listener = None;
+    }
+}
+
+/// ロックファイルを置く、現在の実効ユーザー専用のディレクトリを返します。
+///
+/// `XDG_RUNTIME_DIR`が設定されていればその中に、設定されていなければ一時ディレクトリの中に、
+/// `instance-pipe-<実効ユーザーID>`をパーミッション0700で作成します。
+/// 既に存在する場合は、シンボリックリンクでないこと、所有者が現在のユーザーであること、
+/// 他のユーザーがアクセスできないことを確認します。
+///
+/// # エラー
+/// ディレクトリの作成に失敗した場合や、既存のディレクトリが上記の条件を満たさない場合にエラーを返します。
+fn lock_dir() -> Result<PathBuf> {
+    let base = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .unwrap_or_else(std::env::temp_dir);
+    // SAFETY: geteuidは常に成功し、副作用を持ちません。
+    let uid = unsafe { libc::geteuid() };
+    let dir = base.join(format!("instance-pipe-{}", uid));
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+
+    let metadata = std::fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Lock directory {} must be a directory private to user {}",
+                dir.display(),
+                uid
+            ),
+        ));
+    }
+    Ok(dir)
+}
+
+/// ロックファイルを開き、排他的な`flock`を取得します。
+///
+/// シンボリックリンクを経由して別のファイルを開かないよう、`O_NOFOLLOW`で開きます。
+/// ロックは返されたファイルを閉じると解放されます。
+///
+/// # エラー
+/// ロックファイルを開けない場合や、ロックファイルがシンボリックリンクの場合、
+/// ロックの取得に失敗した場合にエラーを返します。
+fn lock(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .map_err(|e| match (e.kind(), e.raw_os_error()) {
+            (io::ErrorKind::PermissionDenied, _) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Lock file {} is not accessible to the current user", path.display()),
+            ),
+            (_, Some(libc::ELOOP)) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Lock file {} is a symbolic link", path.display()),
+            ),
+            _ => e,
+        })?;
+    loop {
+        // SAFETY: 有効なファイルディスクリプタに対するflockはメモリ安全性に影響しません。
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(file);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// 既存のソケットファイルで誰も待ち受けていないかどうかを確認します。
+///
+/// ソケットファイルの作成は常にロックを保持したまま行われるため、接続を拒否された場合は
+/// 作成途中のサーバーではなく、終了したサーバーが残したファイルとみなせます。
+///
+/// # エラー
+/// ファイルがソケットでない場合や、ファイルの情報の取得に失敗した場合にエラーを返します。
+fn is_stale(path: &Path) -> Result<bool> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) => {}
+        // 確認の間に削除された
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Ok(false),
+        Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound) => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// プラットフォームに応じたソケット名を生成します（Windows用）。
+#[cfg(target_os = "windows")]
+pub(crate) fn socket_name(name: &str) -> String {
+    format!(r"\\.\pipe\{}", name)
+}
+
+/// プラットフォームに応じたソケット名を生成します（非Windows用）。
+#[cfg(not(target_os = "windows"))]
+pub(crate) fn socket_name(name: &str) -> String {
+    format!("/tmp/{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn test_path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("instance-pipe-test-{}-{}", test, std::process::id()))
+    }
+
+    #[test]
+    fn socket_file_is_stale_once_its_listener_is_closed() {
+        let path = test_path("stale");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(!is_stale(&path).unwrap());
+
+        drop(listener);
+        assert!(is_stale(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lock_dir_is_private_to_the_user() {
+        let metadata = std::fs::symlink_metadata(lock_dir().unwrap()).unwrap();
+        assert!(metadata.is_dir());
+        // SAFETY: geteuidは常に成功し、副作用を持ちません。
+        assert_eq!(metadata.uid(), unsafe { libc::geteuid() });
+        assert_eq!(metadata.mode() & 0o077, 0);
+    }
+
+    #[test]
+    fn lock_does_not_follow_symlinks() {
+        let target = test_path("lock-target");
+        let link = test_path("lock-link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let error = lock(&link).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!target.exists());
+        std::fs::remove_file(&link).unwrap();
+    }
+
+    #[test]
+    fn lock_is_exclusive_until_released() {
+        let path = test_path("lock");
+        let held = lock(&path).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let waiting = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let _lock = lock(&path).unwrap();
+                sender.send(()).unwrap();
+            })
+        };
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+
+        drop(held);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiting.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
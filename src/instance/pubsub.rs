@@ -0,0 +1,259 @@
+use crate::protocol::codec::Codec;
+use crate::protocol::{self, Capabilities, Frame, FrameKind, ProtocolError, ProtocolErrorKind};
+use crate::{Client, Server};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, Result};
+use std::time::Instant;
+
+/// トピックの階層を区切る文字。
+pub const TOPIC_SEPARATOR: char = '/';
+
+/// トピックに配信されたメッセージ。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publication<T> {
+    /// メッセージが配信されたトピック。
+    pub topic: String,
+    /// 配信されたメッセージ。
+    pub message: T,
+}
+
+/// 購読できるトピックパターンの最大バイト数。
+///
+/// パターンは接続相手から届くため、照合の負荷を抑えるために長さを制限します。
+pub const MAX_PATTERN_LEN: usize = 1024;
+
+/// トピックパターンがトピックに一致するかどうかを返します。
+///
+/// トピックとパターンは[`TOPIC_SEPARATOR`]で階層に区切られます。パターンでは次のワイルドカードを使用できます。
+///
+/// - `*`: 任意の1階層に一致します（例: `sensors/*/temperature`）。
+/// - `**`: 0個以上の任意の階層に一致します（例: `sensors/**`）。連続した`**`は1つの`**`として扱います。
+///
+/// それ以外の階層は完全に一致する必要があります。
+/// 照合にかかる時間はパターンとトピックの階層数の積に比例します。
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern: Vec<&str> = pattern.split(TOPIC_SEPARATOR).collect();
+    pattern.dedup_by(|segment, previous| *segment == "**" && *previous == "**");
+    let topic: Vec<&str> = topic.split(TOPIC_SEPARATOR).collect();
+    matches_segments(&pattern, &topic)
+}
+
+/// 階層に区切ったパターンとトピックを先頭から照合します。
+///
+/// 最後に現れた`**`の位置だけを記録し、不一致になったらその`**`が吸収する階層を1つ増やしてやり直します。
+fn matches_segments(pattern: &[&str], topic: &[&str]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // 最後の`**`の次のパターン位置と、その`**`が吸収し始めたトピック位置
+    let mut backtrack = None;
+    while t < topic.len() {
+        match pattern.get(p) {
+            Some(&"**") => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&segment) if segment == "*" || segment == topic[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((next, start)) => {
+                    backtrack = Some((next, start + 1));
+                    p = next;
+                    t = start + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&segment| segment == "**")
+}
+
+/// 購読するトピックパターンの長さを検査します。
+pub(crate) fn check_pattern(pattern: &str) -> std::result::Result<(), ProtocolError> {
+    if pattern.len() > MAX_PATTERN_LEN {
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::InvalidFrame,
+            format!("Topic pattern exceeds {} bytes", MAX_PATTERN_LEN),
+        ));
+    }
+    Ok(())
+}
+
+impl Client {
+    /// トピックパターンを購読します。
+    ///
+    /// 以降、サーバーが[`Server::publish`]でパターンに一致するトピックに配信したメッセージを、
+    /// [`poll_publication`](Client::poll_publication)や[`recv_publication`](Client::recv_publication)で受信できます。
+    /// パターンの書式は[`topic_matches`]を参照してください。
+    ///
+    /// # エラー
+    /// 相手が出版/購読に対応していない場合や、パターンが[`MAX_PATTERN_LEN`]を超える場合、
+    /// 送信に失敗した場合にエラーを返します。
+    pub fn subscribe(&self, pattern: &str) -> Result<()> {
+        self.require_pubsub()?;
+        check_pattern(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.write_frame(&Frame {
+            kind: FrameKind::Subscribe,
+            payload: pattern.as_bytes().to_vec(),
+        })
+    }
+
+    /// トピックパターンの購読を解除します。
+    ///
+    /// `pattern`は[`subscribe`](Client::subscribe)に渡したものと同じ文字列である必要があります。
+    ///
+    /// # エラー
+    /// 相手が出版/購読に対応していない場合や、送信に失敗した場合にエラーを返します。
+    pub fn unsubscribe(&self, pattern: &str) -> Result<()> {
+        self.require_pubsub()?;
+        self.write_frame(&Frame {
+            kind: FrameKind::Unsubscribe,
+            payload: pattern.as_bytes().to_vec(),
+        })
+    }
+
+    /// 購読しているトピックに配信されたメッセージをポーリングします。
+    ///
+    /// タイムアウト時間内にメッセージが届かなければNoneを返します。
+    ///
+    /// # エラー
+    /// 接続が閉じられた場合や、メッセージのデシリアライズに失敗した場合にエラーを返します。
+    pub fn poll_publication<T: DeserializeOwned>(&self) -> Result<Option<Publication<T>>> {
+        let deadline = Instant::now() + self.get_timeout();
+        match self.next_frame(FrameKind::Publish, Some(deadline))? {
+            Some(frame) => self.decode_publication(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// 購読しているトピックに配信されたメッセージを、届くまで待って受信します。
+    ///
+    /// # エラー
+    /// 接続が閉じられた場合や、メッセージのデシリアライズに失敗した場合にエラーを返します。
+    pub fn recv_publication<T: DeserializeOwned>(&self) -> Result<Publication<T>> {
+        let frame = self
+            .next_frame(FrameKind::Publish, None)?
+            .ok_or(io::ErrorKind::TimedOut)?;
+        self.decode_publication(frame)
+    }
+
+    /// 受信した配信フレームからトピックとメッセージを取り出します。
+    fn decode_publication<T: DeserializeOwned>(&self, frame: Frame) -> Result<Publication<T>> {
+        let invalid = || ProtocolError::new(ProtocolErrorKind::InvalidFrame, "Malformed publication frame");
+        let (len, rest) = frame.payload.split_first_chunk::<4>().ok_or_else(invalid)?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            return Err(invalid().into());
+        }
+        let (topic, body) = rest.split_at(len);
+        Ok(Publication {
+            topic: String::from_utf8(topic.to_vec()).map_err(|_| invalid())?,
+            message: self.get_config().get_codec().decode(body)?,
+        })
+    }
+
+    /// ハンドシェイクで両端が出版/購読に対応していると確認できたかどうかを検査します。
+    fn require_pubsub(&self) -> Result<()> {
+        if self.get_capabilities().contains(Capabilities::PUBSUB) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Publish/subscribe was not negotiated for this connection",
+            ))
+        }
+    }
+}
+
+impl Server {
+    /// トピックにメッセージを配信します。
+    ///
+    /// メッセージは、トピックに一致するパターンを購読しているクライアントにのみ送信されます。
+    /// 切断により送信に失敗したクライアントは登録から外されます。
+    /// 送信できたクライアントの数を返します。
+    ///
+    /// # 引数
+    /// - `topic`: 配信先のトピック（例: `"sensors/kitchen/temperature"`）。
+    /// - `message`: 配信するメッセージ。
+    ///
+    /// # エラー
+    /// メッセージのシリアライズに失敗した場合や、トピックが長すぎる場合、
+    /// フレームが最大サイズを超える場合にエラーを返します。
+    pub fn publish<T: Serialize>(&self, topic: &str, message: &T) -> Result<usize> {
+        let topic_len = u32::try_from(topic.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Topic is too long"))?;
+        let mut payload = topic_len.to_le_bytes().to_vec();
+        payload.extend_from_slice(topic.as_bytes());
+        payload.extend_from_slice(&self.get_config().get_codec().encode(message)?);
+        let bytes = protocol::encode_frame(
+            &Frame {
+                kind: FrameKind::Publish,
+                payload,
+            },
+            self.get_config(),
+        )?;
+
+        Ok(self.write_to_each(&bytes, |client| {
+            client.get_capabilities().contains(Capabilities::PUBSUB) && client.is_subscribed(topic)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_segments_must_match_exactly() {
+        assert!(topic_matches("sensors/kitchen", "sensors/kitchen"));
+        assert!(!topic_matches("sensors/kitchen", "sensors/hall"));
+        assert!(!topic_matches("sensors/kitchen", "sensors/kitchen/temperature"));
+        assert!(!topic_matches("sensors/kitchen/temperature", "sensors/kitchen"));
+        assert!(topic_matches("", ""));
+    }
+
+    #[test]
+    fn single_wildcard_matches_one_segment() {
+        assert!(topic_matches("sensors/*/temperature", "sensors/kitchen/temperature"));
+        assert!(!topic_matches("sensors/*/temperature", "sensors/temperature"));
+        assert!(!topic_matches("sensors/*/temperature", "sensors/a/b/temperature"));
+        assert!(topic_matches("*", "sensors"));
+        assert!(!topic_matches("*", "sensors/kitchen"));
+    }
+
+    #[test]
+    fn double_wildcard_matches_any_number_of_segments() {
+        assert!(topic_matches("sensors/**", "sensors"));
+        assert!(topic_matches("sensors/**", "sensors/kitchen/temperature"));
+        assert!(topic_matches("**/temperature", "temperature"));
+        assert!(topic_matches("**/temperature", "sensors/kitchen/temperature"));
+        assert!(!topic_matches("**/temperature", "sensors/kitchen/humidity"));
+        assert!(topic_matches("a/**/b/**/c", "a/x/b/y/z/c"));
+        assert!(topic_matches("a/**/b/c", "a/b/x/b/c"));
+        assert!(!topic_matches("a/**/b/c", "a/b/x/c"));
+        assert!(topic_matches("**/*/c", "a/b/c"));
+        assert!(!topic_matches("**/*/c", "c"));
+    }
+
+    #[test]
+    fn consecutive_double_wildcards_collapse() {
+        assert!(topic_matches("a/**/**/**/b", "a/b"));
+        assert!(topic_matches("a/**/**/**/b", "a/x/y/b"));
+        assert!(!topic_matches("a/**/**/**/b", "a/x/y/c"));
+    }
+
+    #[test]
+    fn many_double_wildcards_do_not_backtrack_exponentially() {
+        let pattern = vec!["**/a"; 200].join("/") + "/b";
+        let topic = vec!["a"; 400].join("/");
+        assert!(pattern.len() <= MAX_PATTERN_LEN);
+        assert!(!topic_matches(&pattern, &topic));
+        assert!(topic_matches(&pattern, &(topic + "/b")));
+    }
+
+    #[test]
+    fn long_patterns_are_rejected() {
+        assert!(check_pattern(&"a".repeat(MAX_PATTERN_LEN)).is_ok());
+        assert!(check_pattern(&"a".repeat(MAX_PATTERN_LEN + 1)).is_err());
+    }
+}
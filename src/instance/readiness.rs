@@ -0,0 +1,67 @@
+use std::io::Result;
+use std::time::Duration;
+
+/// OSのレディネス通知を利用できない環境で、状態を再確認するまでの待機時間。
+#[cfg(not(unix))]
+pub(crate) const FALLBACK_DELAY: Duration = Duration::from_millis(10);
+
+/// 待機する操作の種類。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Interest {
+    /// 読み込み可能になるか、相手が切断するまで待ちます。
+    Read,
+    /// 書き込み可能になるまで待ちます。
+    Write,
+    /// 相手の切断のみを待ちます。届いたデータでは起床しません。
+    Hangup,
+}
+
+/// 待機対象のファイルディスクリプタと操作の種類の組を`pollfd`に変換します（Unix用）。
+#[cfg(unix)]
+pub(crate) fn pollfd(fd: std::os::fd::BorrowedFd<'_>, interest: Interest) -> libc::pollfd {
+    use std::os::fd::AsRawFd;
+
+    let events = match interest {
+        Interest::Read => libc::POLLIN,
+        Interest::Write => libc::POLLOUT,
+        // POLLHUPとPOLLERRは要求しなくても常に報告される
+        Interest::Hangup => 0,
+    };
+    libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events,
+        revents: 0,
+    }
+}
+
+/// いずれかのファイルディスクリプタが準備できるか、`timeout`が経過するまで待ちます（Unix用）。
+///
+/// `timeout`がNoneの場合は無期限に待ちます。準備できたファイルディスクリプタの数を返し、
+/// 各`pollfd`の`revents`に結果が設定されます。シグナルで中断された場合は`0`を返します。
+#[cfg(unix)]
+pub(crate) fn poll(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> Result<usize> {
+    let timeout = match timeout {
+        // 1ミリ秒未満の端数は切り上げ、期限より前に起床して空回りしないようにする
+        Some(timeout) => timeout
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    };
+    // SAFETY: `fds`は有効な`pollfd`のスライスで、呼び出し中は排他的に借用されています。
+    let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+    if ready < 0 {
+        let error = std::io::Error::last_os_error();
+        if error.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(0);
+        }
+        return Err(error);
+    }
+    Ok(ready as usize)
+}
+
+/// レディネス通知を利用できない環境で、次に状態を確認するまで待ちます（非Unix用）。
+#[cfg(not(unix))]
+pub(crate) fn sleep(timeout: Option<Duration>) {
+    std::thread::sleep(timeout.map_or(FALLBACK_DELAY, |timeout| timeout.min(FALLBACK_DELAY)));
+}
@@ -0,0 +1,547 @@
+use crate::instance::event::{Event, EventHandler};
+use crate::protocol::codec::Codec;
+use crate::protocol::{Frame, ProtocolConfig};
+use crate::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::{self, Result};
+use std::time::{Duration, Instant};
+
+/// 最初の再接続を試みるまでの既定の待ち時間。
+pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(100);
+/// 再接続の間隔の既定の上限。
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// 再接続の間隔と回数を設定する構造体。
+///
+/// `n`回目の試行までの待ち時間は`initial_delay * multiplier^(n - 1)`を`max_delay`で打ち切った値で、
+/// そこからさらに最大`jitter`の割合だけランダムに短縮されます。
+/// ジッターにより、サーバーの再起動時に多数のクライアントが同時に接続し直すことを避けられます。
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReconnectPolicy {
+    /// 既定値で設定を作成します。
+    ///
+    /// 最初の待ち時間は[`DEFAULT_INITIAL_DELAY`]、上限は[`DEFAULT_MAX_DELAY`]、倍率は2、
+    /// ジッターは0.5で、再接続を諦めることはありません。
+    pub fn new() -> Self {
+        Self {
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+
+    /// 最初の再接続を試みるまでの待ち時間を設定します。
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// 再接続の間隔の上限を設定します。
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// 試行ごとに待ち時間を何倍にするかを設定します。1未満の値は1として扱われます。
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// 待ち時間をランダムに短縮する最大の割合を設定します。値は0から1の範囲に収められます。
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// 再接続を諦めるまでの最大試行回数を設定します。
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// 現在の最初の待ち時間を取得します。
+    pub fn get_initial_delay(&self) -> Duration {
+        self.initial_delay
+    }
+
+    /// 現在の間隔の上限を取得します。
+    pub fn get_max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    /// 現在の倍率を取得します。
+    pub fn get_multiplier(&self) -> f64 {
+        self.multiplier
+    }
+
+    /// 現在のジッターの割合を取得します。
+    pub fn get_jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// 現在の最大試行回数を取得します。制限がない場合はNoneです。
+    pub fn get_max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+
+    /// `attempt`回目の試行までの待ち時間を、ジッターを適用して計算します。
+    fn delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt.saturating_sub(1)).unwrap_or(i32::MAX);
+        let backoff = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        Duration::from_secs_f64(backoff * (1.0 - self.jitter * random_unit()))
+    }
+}
+
+/// 0以上1未満の乱数を返します。
+///
+/// `RandomState`は作成のたびに異なるキーを持つため、空の入力のハッシュ値を乱数として使用します。
+fn random_unit() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let bits = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// [`ReconnectingClient`]で発生したイベント。
+///
+/// 今後のバージョンでバリアントが追加される可能性があるため、`match`ではワイルドカードの腕が必要です。
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum ReconnectEvent<T> {
+    /// 現在の接続で発生したイベント。
+    Connection(Event<T>),
+    /// 切断された接続の再接続を試みます。
+    ///
+    /// `attempt`は1から始まる試行回数、`delay`はその試行までの待ち時間です。
+    Reconnecting { attempt: u32, delay: Duration },
+    /// 再接続に成功しました。`attempts`は成功までに要した試行回数です。
+    Reconnected { attempts: u32 },
+}
+
+/// 切断されると自動的に再接続するクライアント。
+///
+/// [`poll_event`](ReconnectingClient::poll_event)で切断を検出すると、[`ReconnectPolicy`]に従って
+/// 間隔を空けながら接続し直します。再接続の状況は[`ReconnectEvent::Reconnecting`]と[`ReconnectEvent::Reconnected`]で通知されます。
+///
+/// [`set_hello`](ReconnectingClient::set_hello)で設定したメッセージと、
+/// [`subscribe`](ReconnectingClient::subscribe)で購読したパターンは、接続し直すたびに自動的に再送されます。
+/// 再接続中に送信しようとしたメッセージは保持されず、エラーになります。
+pub struct ReconnectingClient {
+    name: String,
+    config: ProtocolConfig,
+    policy: ReconnectPolicy,
+    state: State,
+    /// 接続するたびに送信する、エンコード済みのメッセージ。
+    hello: Option<Vec<u8>>,
+    /// 接続するたびに購読し直すトピックのパターン。
+    subscriptions: BTreeSet<String>,
+    event_handler: EventHandler,
+    timeout: Duration,
+}
+
+/// 接続の状態。
+enum State {
+    Connected(Client),
+    /// 次の再接続の試行を待っています。
+    Waiting {
+        attempt: u32,
+        delay: Duration,
+        at: Instant,
+        /// [`ReconnectEvent::Reconnecting`]を返したかどうか。
+        announced: bool,
+    },
+    /// 最大試行回数に達し、再接続を諦めました。
+    Failed,
+}
+
+impl ReconnectingClient {
+    /// 指定された名前のサーバーに接続を開始します。
+    ///
+    /// 最初の接続は再試行しません。サーバーが起動していない場合はエラーを返します。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    /// - `policy`: 切断後に再接続する間隔と回数。
+    ///
+    /// # エラー
+    /// 接続に失敗した場合や、サーバーとのハンドシェイクに失敗した場合にエラーを返します。
+    pub fn start(name: &str, policy: ReconnectPolicy) -> Result<Self> {
+        Self::start_with(name, ProtocolConfig::default(), policy)
+    }
+
+    /// 指定されたプロトコル設定でサーバーに接続を開始します。
+    ///
+    /// 設定は、再接続した接続にも適用されます。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    /// - `config`: 接続に適用するプロトコルの設定。
+    /// - `policy`: 切断後に再接続する間隔と回数。
+    ///
+    /// # エラー
+    /// 接続に失敗した場合や、サーバーとのハンドシェイクに失敗した場合にエラーを返します。
+    pub fn start_with(name: &str, config: ProtocolConfig, policy: ReconnectPolicy) -> Result<Self> {
+        let mut client = Self {
+            name: name.to_string(),
+            config,
+            policy,
+            state: State::Failed,
+            hello: None,
+            subscriptions: BTreeSet::new(),
+            event_handler: EventHandler::new(),
+            timeout: Duration::from_millis(50), // Default timeout of 50ms
+        };
+        client.state = State::Connected(client.connect()?);
+        Ok(client)
+    }
+
+    /// サーバーからのイベントをポーリングします。
+    ///
+    /// 接続中は[`Client::poll_event`]のイベントを[`ReconnectEvent::Connection`]として返します。
+    /// 切断を検出すると[`Event::Disconnected`]を返し、以降のポーリングで再接続を進めます。
+    /// 再接続の試行前には[`ReconnectEvent::Reconnecting`]を、成功した場合は[`ReconnectEvent::Reconnected`]を返します。
+    /// 待ち時間がタイムアウト時間より長い場合は、タイムアウト時間だけ待ってNoneを返します。
+    ///
+    /// # エラー
+    /// メッセージのデシリアライズに失敗した場合や、最大試行回数に達して再接続を諦めた場合にエラーを返します。
+    pub fn poll_event<T: for<'a> Deserialize<'a> + 'static>(&mut self) -> Result<Option<ReconnectEvent<T>>> {
+        match &mut self.state {
+            State::Connected(client) => {
+                let event = client.poll_event()?;
+                if matches!(event, Some(Event::Disconnected { .. })) {
+                    self.schedule(1);
+                }
+                Ok(event.map(ReconnectEvent::Connection))
+            }
+            State::Waiting {
+                attempt,
+                delay,
+                at,
+                announced,
+            } => {
+                let (attempt, delay, at) = (*attempt, *delay, *at);
+                if !*announced {
+                    *announced = true;
+                    return Ok(Some(self.notify(ReconnectEvent::Reconnecting { attempt, delay })));
+                }
+                let remaining = at.saturating_duration_since(Instant::now());
+                if !remaining.is_zero() {
+                    std::thread::sleep(remaining.min(self.timeout));
+                    if remaining > self.timeout {
+                        return Ok(None);
+                    }
+                }
+
+                match self.connect() {
+                    Ok(client) => {
+                        self.state = State::Connected(client);
+                        Ok(Some(self.notify(ReconnectEvent::Reconnected { attempts: attempt })))
+                    }
+                    Err(e) if self.policy.max_attempts.is_some_and(|max| attempt >= max) => {
+                        self.state = State::Failed;
+                        Err(e)
+                    }
+                    Err(_) => {
+                        // 次の試行を予約し、その[`ReconnectEvent::Reconnecting`]を返す
+                        self.schedule(attempt + 1);
+                        self.poll_event()
+                    }
+                }
+            }
+            State::Failed => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Gave up reconnecting to the server",
+            )),
+        }
+    }
+
+    /// サーバーにメッセージを送信します。
+    ///
+    /// 再接続中は送信できません。送信中に切断を検出した場合は、再接続を開始してからエラーを返します。
+    ///
+    /// # エラー
+    /// 再接続中の場合や、メッセージのシリアライズまたは送信に失敗した場合にエラーを返します。
+    pub fn send<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        let client = self.connected()?;
+        let result = client.send(message);
+        if let Some(reason) = client.get_disconnect_reason() {
+            client.notify_disconnected(reason);
+            self.schedule(1);
+        }
+        result
+    }
+
+    /// サーバーからメッセージを受信します。
+    ///
+    /// メッセージが届くまで待ちます。待っている間に切断された場合は、再接続してから受信を続けます。
+    ///
+    /// # エラー
+    /// メッセージのデシリアライズに失敗した場合や、最大試行回数に達して再接続を諦めた場合にエラーを返します。
+    pub fn recv<T: for<'a> Deserialize<'a> + 'static>(&mut self) -> Result<T> {
+        loop {
+            if let Some(ReconnectEvent::Connection(Event::MessageReceived(message))) = self.poll_event()? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// 接続するたびにサーバーへ送信するメッセージを設定します。
+    ///
+    /// 接続中であれば、すぐに送信します。クライアントの登録など、サーバーが接続ごとに必要とする
+    /// 初期化のメッセージに使用します。
+    ///
+    /// # エラー
+    /// メッセージのシリアライズまたは送信に失敗した場合にエラーを返します。
+    pub fn set_hello<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        let payload = self.config.get_codec().encode(message)?;
+        self.hello = Some(payload.clone());
+        match &self.state {
+            State::Connected(client) => client.write_frame(&Frame::data(payload)),
+            _ => Ok(()),
+        }
+    }
+
+    /// トピックパターンを購読します。購読は再接続後も維持されます。
+    ///
+    /// 詳細は[`Client::subscribe`]を参照してください。
+    ///
+    /// # エラー
+    /// 相手が出版/購読に対応していない場合や、送信に失敗した場合にエラーを返します。
+    pub fn subscribe(&mut self, pattern: &str) -> Result<()> {
+        if let State::Connected(client) = &self.state {
+            client.subscribe(pattern)?;
+        }
+        self.subscriptions.insert(pattern.to_string());
+        Ok(())
+    }
+
+    /// トピックパターンの購読を解除します。
+    ///
+    /// # エラー
+    /// 相手が出版/購読に対応していない場合や、送信に失敗した場合にエラーを返します。
+    pub fn unsubscribe(&mut self, pattern: &str) -> Result<()> {
+        self.subscriptions.remove(pattern);
+        match &self.state {
+            State::Connected(client) => client.unsubscribe(pattern),
+            _ => Ok(()),
+        }
+    }
+
+    /// 現在の接続を返します。再接続中はNoneを返します。
+    pub fn client(&self) -> Option<&Client> {
+        match &self.state {
+            State::Connected(client) => Some(client),
+            _ => None,
+        }
+    }
+
+    /// サーバーとの接続が維持されているかどうかを返します。
+    pub fn is_connected(&self) -> bool {
+        self.client().is_some_and(Client::is_connected)
+    }
+
+    /// 現在のタイムアウト時間を取得します。
+    pub fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// タイムアウト時間を設定します。再接続した接続にも適用されます。
+    ///
+    /// # 引数
+    /// - `timeout`: ポーリング時の新しいタイムアウト時間。
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        if let State::Connected(client) = &mut self.state {
+            client.set_timeout(timeout);
+        }
+    }
+
+    /// 再接続の設定を取得します。
+    pub fn get_policy(&self) -> &ReconnectPolicy {
+        &self.policy
+    }
+
+    /// このクライアントのイベントを通知するハンドラーを返します。
+    ///
+    /// ハンドラーは再接続した接続にも引き継がれます。
+    pub fn event_handler(&self) -> &EventHandler {
+        &self.event_handler
+    }
+
+    /// サーバーに接続し、設定済みのメッセージと購読を再送します。
+    fn connect(&self) -> Result<Client> {
+        let mut client = Client::start_with(&self.name, self.config.clone())?;
+        client.set_timeout(self.timeout);
+        client.set_event_handler(self.event_handler.clone());
+        if let Some(payload) = &self.hello {
+            client.write_frame(&Frame::data(payload.clone()))?;
+        }
+        for pattern in &self.subscriptions {
+            client.subscribe(pattern)?;
+        }
+        Ok(client)
+    }
+
+    /// `attempt`回目の再接続の試行を予約します。
+    fn schedule(&mut self, attempt: u32) {
+        let delay = self.policy.delay(attempt);
+        self.state = State::Waiting {
+            attempt,
+            delay,
+            at: Instant::now() + delay,
+            announced: false,
+        };
+    }
+
+    /// 接続中のクライアントを返します。再接続中の場合はエラーを返します。
+    fn connected(&self) -> Result<&Client> {
+        match &self.state {
+            State::Connected(client) => Ok(client),
+            State::Waiting { .. } => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Reconnecting to the server",
+            )),
+            State::Failed => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Gave up reconnecting to the server",
+            )),
+        }
+    }
+
+    /// 再接続のイベントをハンドラーのログに記録し、そのまま返します。
+    ///
+    /// 接続のイベントは、ハンドラーを共有する接続自身が通知するため記録しません。
+    fn notify<T>(&self, event: ReconnectEvent<T>) -> ReconnectEvent<T> {
+        match &event {
+            ReconnectEvent::Connection(_) => {}
+            ReconnectEvent::Reconnecting { .. } => self.event_handler.record("Reconnecting"),
+            ReconnectEvent::Reconnected { .. } => self.event_handler.record("Reconnected"),
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// ジッターのない、短い間隔で再接続する設定。
+    fn fast_policy() -> ReconnectPolicy {
+        ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(10))
+            .max_delay(Duration::from_millis(50))
+            .jitter(0.0)
+    }
+
+    /// サーバーを起動し、`policy`で接続した再接続クライアントとサーバーを返します。
+    fn connect(name: &str, policy: ReconnectPolicy) -> (Server, ReconnectingClient) {
+        let mut server = Server::start(name).unwrap();
+        let connecting = thread::spawn({
+            let name = name.to_string();
+            move || ReconnectingClient::start(&name, policy)
+        });
+        server.accept().unwrap();
+        (server, connecting.join().unwrap().unwrap())
+    }
+
+    /// 再接続に関係するイベントを名前に変換します。
+    fn label(event: &ReconnectEvent<u32>) -> Option<&'static str> {
+        match event {
+            ReconnectEvent::Connection(Event::Disconnected { .. }) => Some("disconnected"),
+            ReconnectEvent::Reconnecting { .. } => Some("reconnecting"),
+            ReconnectEvent::Reconnected { .. } => Some("reconnected"),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn reconnects_after_server_restart_and_replays_subscriptions() {
+        let name = format!("instance-pipe-test-reconnect-{}", std::process::id());
+        let (server, mut client) = connect(&name, fast_policy());
+        client.subscribe("news/*").unwrap();
+        drop(server);
+
+        let (restart, restarted) = mpsc::channel();
+        let polling = thread::spawn(move || {
+            let mut labels = Vec::new();
+            while labels.last() != Some(&"reconnected") {
+                let Some(event) = client.poll_event::<u32>().unwrap() else {
+                    continue;
+                };
+                if let Some(label) = label(&event) {
+                    // 最初の再接続の試行が予約されたら、サーバーを起動し直す
+                    if label == "reconnecting" && !labels.contains(&"reconnecting") {
+                        restart.send(()).unwrap();
+                    }
+                    labels.push(label);
+                }
+            }
+            (client, labels)
+        });
+
+        restarted.recv_timeout(Duration::from_secs(5)).unwrap();
+        let mut server = Server::start(&name).unwrap();
+        server.accept().unwrap();
+        let (client, labels) = polling.join().unwrap();
+        assert_eq!(labels.first(), Some(&"disconnected"));
+        assert_eq!(labels[1], "reconnecting");
+        assert_eq!(labels.last(), Some(&"reconnected"));
+
+        // 購読は再接続した接続でも送り直されている
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.publish("news/today", &7u32).unwrap() == 0 {
+            assert!(Instant::now() < deadline, "subscription was not replayed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let publication = client.client().unwrap().recv_publication::<u32>().unwrap();
+        assert_eq!((publication.topic.as_str(), publication.message), ("news/today", 7));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let name = format!("instance-pipe-test-reconnect-give-up-{}", std::process::id());
+        let (server, mut client) = connect(&name, fast_policy().max_attempts(2));
+        drop(server);
+
+        let mut reconnecting = Vec::new();
+        let error = loop {
+            match client.poll_event::<u32>() {
+                Ok(Some(ReconnectEvent::Reconnecting { attempt, delay })) => reconnecting.push((attempt, delay)),
+                Ok(_) => {}
+                Err(e) => break e,
+            }
+        };
+        // 待ち時間は試行ごとに倍率を掛けて延びる
+        assert_eq!(
+            reconnecting,
+            [(1, Duration::from_millis(10)), (2, Duration::from_millis(20))]
+        );
+        // 諦めた時点では最後の試行での接続のエラーが返され、以降は再接続を諦めたことを示すエラーになる
+        assert_ne!(error.kind(), io::ErrorKind::NotConnected);
+        assert!(!client.is_connected());
+        assert_eq!(client.poll_event::<u32>().err().unwrap().kind(), io::ErrorKind::NotConnected);
+        assert_eq!(client.send(&1u32).err().unwrap().kind(), io::ErrorKind::NotConnected);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::protocol::codec::Codec;
+use crate::protocol::{Capabilities, Frame, FrameKind, ProtocolError, ProtocolErrorKind};
+use crate::Client;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, Result};
+use std::time::{Duration, Instant};
+
+/// レスポンスの状態: 呼び出しが成功し、本体にエンコード済みの値が続きます。
+const STATUS_OK: u8 = 0;
+/// レスポンスの状態: 呼び出しが失敗し、本体にUTF-8のエラーメッセージが続きます。
+const STATUS_ERR: u8 = 1;
+
+impl Client {
+    /// 相手にRPCリクエストを送信し、対応するレスポンスを待ちます。
+    ///
+    /// 各リクエストには相関IDが付与されるため、相手が他のメッセージを先に送信した場合や、
+    /// クローンした同じクライアントから複数のスレッドが同時に呼び出した場合でも、
+    /// 自身のリクエストに対するレスポンスのみを受け取ります。
+    /// 途中で届いた通常のメッセージは[`poll_event`](Client::poll_event)や[`recv`](Client::recv)で受け取れます。
+    ///
+    /// # 引数
+    /// - `request`: 送信するリクエスト。
+    /// - `timeout`: レスポンスを待つ最大時間。
+    ///
+    /// # エラー
+    /// 相手がRPCに対応していない場合や、タイムアウト時間内にレスポンスが届かない場合、
+    /// 相手がエラーを返した場合にエラーを返します。
+    pub fn call<Req: Serialize, Resp: DeserializeOwned>(&self, request: &Req, timeout: Duration) -> Result<Resp> {
+        let deadline = Instant::now() + timeout;
+        self.require_rpc()?;
+
+        let id = self.begin_call();
+        let mut payload = id.to_le_bytes().to_vec();
+        let sent = self
+            .get_config()
+            .get_codec()
+            .encode(request)
+            .and_then(|encoded| {
+                payload.extend_from_slice(&encoded);
+                self.write_frame(&Frame {
+                    kind: FrameKind::Request,
+                    payload,
+                })
+            });
+        if let Err(e) = sent {
+            self.cancel_call(id);
+            return Err(e);
+        }
+
+        let Some(response) = self.wait_response(id, deadline)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No response to RPC request {} within {:?}", id, timeout),
+            ));
+        };
+        match response.split_first() {
+            Some((&STATUS_OK, body)) => self.get_config().get_codec().decode(body),
+            Some((&STATUS_ERR, message)) => Err(io::Error::other(String::from_utf8_lossy(message).into_owned())),
+            _ => Err(ProtocolError::new(
+                ProtocolErrorKind::InvalidFrame,
+                format!("Malformed response to RPC request {}", id),
+            )
+            .into()),
+        }
+    }
+
+    /// 相手からのRPCリクエストを、届くまで待って受信します。
+    ///
+    /// # エラー
+    /// 接続が閉じられた場合や、リクエストのデシリアライズに失敗した場合にエラーを返します。
+    pub fn recv_request<T: DeserializeOwned>(&self) -> Result<RpcRequest<T>> {
+        self.require_rpc()?;
+        let frame = self
+            .next_frame(FrameKind::Request, None)?
+            .ok_or(io::ErrorKind::TimedOut)?;
+        self.decode_request(frame)
+    }
+
+    /// 相手からのRPCリクエストをポーリングします。
+    ///
+    /// タイムアウト時間内にリクエストが届かなければNoneを返します。
+    ///
+    /// # エラー
+    /// 接続が閉じられた場合や、リクエストのデシリアライズに失敗した場合にエラーを返します。
+    pub fn poll_request<T: DeserializeOwned>(&self) -> Result<Option<RpcRequest<T>>> {
+        self.require_rpc()?;
+        let deadline = Instant::now() + self.get_timeout();
+        match self.next_frame(FrameKind::Request, Some(deadline))? {
+            Some(frame) => self.decode_request(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// 受信したリクエストフレームから相関IDと本体を取り出します。
+    pub(crate) fn decode_request<T: DeserializeOwned>(&self, frame: Frame) -> Result<RpcRequest<T>> {
+        let Some((id, body)) = frame.payload.split_first_chunk::<8>() else {
+            return Err(ProtocolError::new(
+                ProtocolErrorKind::InvalidFrame,
+                "RPC request is missing its correlation id",
+            )
+            .into());
+        };
+        let id = u64::from_le_bytes(*id);
+        match self.get_config().get_codec().decode(body) {
+            Ok(request) => Ok(RpcRequest {
+                id,
+                request,
+                responder: self.clone(),
+            }),
+            Err(e) => {
+                // 呼び出し元がタイムアウトまで待ち続けないよう、デコードできなかったことを通知する
+                let _ = self.write_response(id, STATUS_ERR, e.to_string().as_bytes());
+                Err(e)
+            }
+        }
+    }
+
+    /// 相関IDと状態を付けてレスポンスを送信します。
+    fn write_response(&self, id: u64, status: u8, body: &[u8]) -> Result<()> {
+        let mut payload = Vec::with_capacity(9 + body.len());
+        payload.extend_from_slice(&id.to_le_bytes());
+        payload.push(status);
+        payload.extend_from_slice(body);
+        let written = self.write_frame(&Frame {
+            kind: FrameKind::Response,
+            payload,
+        });
+        self.answered(id);
+        written
+    }
+
+    /// ハンドシェイクで両端がRPCに対応していると確認できたかどうかを検査します。
+    fn require_rpc(&self) -> Result<()> {
+        if self.get_capabilities().contains(Capabilities::RPC) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "RPC was not negotiated for this connection",
+            ))
+        }
+    }
+}
+
+/// 相手から受信したRPCリクエスト。
+///
+/// [`respond`](RpcRequest::respond)または[`respond_err`](RpcRequest::respond_err)で、
+/// 呼び出し元にレスポンスを返します。
+#[derive(Clone)]
+pub struct RpcRequest<T> {
+    id: u64,
+    request: T,
+    responder: Client,
+}
+
+impl<T> RpcRequest<T> {
+    /// リクエストの相関IDを返します。
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// リクエストの内容への参照を返します。
+    pub fn request(&self) -> &T {
+        &self.request
+    }
+
+    /// リクエストの内容を取り出します。
+    ///
+    /// 取り出した後もレスポンスを返せるよう、レスポンダーを残した`RpcRequest<()>`も返します。
+    pub fn into_parts(self) -> (T, RpcRequest<()>) {
+        (
+            self.request,
+            RpcRequest {
+                id: self.id,
+                request: (),
+                responder: self.responder,
+            },
+        )
+    }
+
+    /// 呼び出し元に成功のレスポンスを返します。
+    ///
+    /// # エラー
+    /// レスポンスのシリアライズまたは送信に失敗した場合にエラーを返します。
+    pub fn respond<R: Serialize>(&self, response: &R) -> Result<()> {
+        let encoded = self.responder.get_config().get_codec().encode(response)?;
+        self.responder.write_response(self.id, STATUS_OK, &encoded)
+    }
+
+    /// 呼び出し元にエラーのレスポンスを返します。
+    ///
+    /// 呼び出し元の[`Client::call`]は、`message`を含むエラーを返します。
+    ///
+    /// # エラー
+    /// レスポンスの送信に失敗した場合にエラーを返します。
+    pub fn respond_err(&self, message: &str) -> Result<()> {
+        self.responder.write_response(self.id, STATUS_ERR, message.as_bytes())
+    }
+}
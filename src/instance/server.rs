@@ -1,96 +1,444 @@
 use crate::Client;
 use interprocess::local_socket::traits::Listener;
-use interprocess::local_socket::{GenericNamespaced, NameType, ToFsName, ListenerNonblockingMode};
-use interprocess::local_socket::{ListenerOptions, ToNsName, prelude::LocalSocketListener};
-use interprocess::os::unix::local_socket::FilesystemUdSocket;
+use interprocess::local_socket::ListenerNonblockingMode;
+use interprocess::local_socket::{ListenerOptions, prelude::{LocalSocketListener, LocalSocketStream}};
 use std::io::Result;
-use crate::instance::event::{Event, EventHandler};
-use std::time::Duration;
+use crate::instance::event::{DisconnectReason, Event, EventHandler};
+use crate::instance::handler::ShutdownHandle;
+use crate::instance::name;
+use crate::instance::readiness::{self, Interest};
+use crate::protocol::codec::Codec;
+use crate::protocol::{self, Capabilities, Frame, FrameKind, ProtocolConfig, ProtocolError, ProtocolErrorKind, HELLO_LEN};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// 終了処理中に、他のスレッドによるレスポンスの送信を確認する間隔。
+///
+/// レスポンスの送信はレディネス通知で検出できないため、この間隔で処理中のやり取りを確認し直します。
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// サーバーが受け入れた接続を識別するID。
+///
+/// IDはサーバーごとに1から順に割り当てられ、接続が切断されても再利用されません。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// 数値から接続IDを作成します。
+    pub(crate) fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    /// IDの数値を返します。
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// 以前のサーバーが残したソケットファイルを見つけた場合の処理方法。
+///
+/// ファイルシステムソケットを使用している場合のみ適用されます。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StaleSocketPolicy {
+    /// 誰も待ち受けていないことを確認してからファイルを削除し、待ち受けを開始します。
+    #[default]
+    Reclaim,
+    /// ファイルを削除せず、[`AddrInUse`](io::ErrorKind::AddrInUse)エラーを返します。
+    Refuse,
+}
 
 /// クライアントからの接続を待ち受けるサーバー構造体。
+///
+/// 受け入れた接続は接続IDとともに登録され、[`send_to`](Server::send_to)や
+/// [`broadcast`](Server::broadcast)で送信できます。切断された接続は自動的に登録から外されます。
+///
+/// ファイルシステムソケットを使用している場合、ソケットファイルはサーバーのドロップ時または
+/// [`shutdown`](Server::shutdown)時に削除されます。
 pub struct Server {
-    listener: LocalSocketListener,
+    /// 接続を待ち受けるリスナー。[`shutdown`](Server::shutdown)で閉じられた後はNone。
+    listener: Option<name::Listening<LocalSocketListener>>,
+    config: ProtocolConfig,
+    connections: Mutex<BTreeMap<ConnectionId, Client>>,
+    next_id: u64,
+    /// まだ[`poll_event`](Server::poll_event)で返していないイベント。
+    pending: Mutex<VecDeque<Event<Client>>>,
+    /// 受け入れたが、まだハンドシェイクが完了していない接続。
+    handshakes: Vec<Handshake>,
+    shutdown: ShutdownHandle,
     event_handler: EventHandler,
     timeout: Duration,
 }
 
+/// 受け入れてからハンドシェイクが完了するまでの接続。
+///
+/// 相手のハンドシェイクメッセージは読み込める分ずつ組み立てるため、
+/// 応答の遅いクライアントがいても他の接続の処理を妨げません。
+struct Handshake {
+    client: Client,
+    hello: [u8; HELLO_LEN],
+    filled: usize,
+    deadline: Instant,
+}
+
 impl Server {
     /// 新しいサーバーインスタンスを作成し、接続の待ち受けを開始します。
     ///
     /// 指定された名前で名前付きパイプまたはソケットを生成します。
+    /// クラッシュしたサーバーが残したソケットファイルがあれば、削除してから待ち受けを開始します。
     ///
     /// # 引数
     /// - `name`: パイプまたはソケットの名前。
     ///
     /// # エラー
-    /// パイプ/ソケットの作成に失敗した場合や、サポートされていないソケットタイプの場合にエラーを返します。
+    /// パイプ/ソケットの作成に失敗した場合や、サポートされていないソケットタイプの場合、
+    /// 他のサーバーが同じ名前で待ち受けている場合にエラーを返します。
     pub fn start(name: &str) -> Result<Self> {
-        let name = socket_name(name);
-        let socket_name = if GenericNamespaced::is_supported() {
-            name.to_ns_name::<GenericNamespaced>()?
-        } else if FilesystemUdSocket::is_supported() {
-            name.to_fs_name::<FilesystemUdSocket>()?
-        } else {
-            panic!("Unsupported");
-        };
-        let opts = ListenerOptions::new().name(socket_name);
-        let listener = opts.create_sync()?;
+        Self::start_with(name, ProtocolConfig::default())
+    }
+
+    /// 指定されたプロトコル設定でサーバーを作成し、接続の待ち受けを開始します。
+    ///
+    /// 設定は、このサーバーが受け入れたすべての接続に適用されます。
+    ///
+    /// # 引数
+    /// - `name`: パイプまたはソケットの名前。
+    /// - `config`: 受け入れた接続に適用するプロトコルの設定。
+    ///
+    /// # エラー
+    /// パイプ/ソケットの作成に失敗した場合や、サポートされていないソケットタイプの場合、
+    /// 他のサーバーが同じ名前で待ち受けている場合にエラーを返します。
+    pub fn start_with(name: &str, config: ProtocolConfig) -> Result<Self> {
+        Self::start_with_policy(name, config, StaleSocketPolicy::default())
+    }
+
+    /// 指定されたプロトコル設定と、残存したソケットファイルの処理方法でサーバーを作成します。
+    ///
+    /// 同じ名前のソケットファイルが既に存在する場合は、接続を試みて待ち受けているサーバーがあるかを確認します。
+    /// 誰も待ち受けていなければ、`stale`に従ってファイルを削除するか、エラーを返します。
+    /// 待ち受けているサーバーでは、確認のための接続が切断イベントとして通知されます。
+    ///
+    /// # 引数
+    /// - `name`: パイプまたはソケットの名前。
+    /// - `config`: 受け入れた接続に適用するプロトコルの設定。
+    /// - `stale`: 残存したソケットファイルを見つけた場合の処理方法。
+    ///
+    /// # エラー
+    /// パイプ/ソケットの作成に失敗した場合や、サポートされていないソケットタイプの場合、
+    /// 他のサーバーが同じ名前で待ち受けている場合、`stale`が[`StaleSocketPolicy::Refuse`]で
+    /// 残存したソケットファイルがある場合、既存のファイルがソケットでない場合にエラーを返します。
+    pub fn start_with_policy(name: &str, config: ProtocolConfig, stale: StaleSocketPolicy) -> Result<Self> {
+        let listener = name::listen(name, stale == StaleSocketPolicy::Reclaim, ListenerOptions::create_sync)?;
         Ok(Self {
-            listener,
+            listener: Some(listener),
+            config,
+            connections: Mutex::new(BTreeMap::new()),
+            next_id: 1,
+            pending: Mutex::new(VecDeque::new()),
+            handshakes: Vec::new(),
+            shutdown: ShutdownHandle::new(),
             event_handler: EventHandler::new(),
             timeout: Duration::from_millis(50), // Default timeout of 50ms
         })
     }
 
-    /// サーバーを停止し、リスナーを閉じます。
+    /// サーバーを停止し、リスナーとすべての接続を閉じます。
+    ///
+    /// 猶予期間なしで[`shutdown`](Server::shutdown)を呼び出すのと同じです。
     pub fn stop(&mut self) -> Result<()> {
-        // LocalSocketListenerは明示的なcloseを持たないため、ドロップで対応
+        self.shutdown(Duration::ZERO)
+    }
+
+    /// サーバーを終了します。
+    ///
+    /// まず新しい接続の受け入れを停止し、goodbyeフレームに対応している接続に終了を通知します。
+    /// 相手が読み込まずに期限までに通知を送信できない接続は、その時点で閉じます。
+    /// その後、最大`grace`の間、各接続で処理中のやり取りが終わるのを待ち、終わった接続から順に閉じます。
+    /// 受信済みでまだレスポンスを返していないRPCリクエストと、応答を待っているRPC呼び出しを処理中とみなします。
+    /// この間も登録済みの接続では送受信でき、別のスレッドが保持している接続からの応答も送信されます。
+    /// 猶予期間が過ぎても残っている接続は強制的に閉じられます。
+    ///
+    /// ファイルシステム上のソケットを使用している場合、ソケットファイルはリスナーを閉じる際に削除されます。
+    /// 終了後は[`run`](Server::run)がすぐに戻り、[`accept`](Server::accept)はエラーを返します。
+    ///
+    /// # 引数
+    /// - `grace`: クライアントが接続を閉じるのを待つ最大時間。
+    ///
+    /// # エラー
+    /// 接続の状態の待機に失敗した場合にエラーを返します。
+    pub fn shutdown(&mut self, grace: Duration) -> Result<()> {
+        let deadline = Instant::now() + grace;
+        self.listener = None;
+        self.handshakes.clear();
+        self.shutdown.shutdown();
+
+        // 読み込まない相手がいても登録を操作できるよう、ロックを解放してから期限付きで送信する
+        let goodbye = Frame {
+            kind: FrameKind::Goodbye,
+            payload: Vec::new(),
+        };
+        for client in self.connections() {
+            if client.get_capabilities().contains(Capabilities::GOODBYE)
+                && client.write_frame_until(&goodbye, deadline).is_err()
+            {
+                client.close();
+            }
+        }
+        self.retain_connections(Client::is_connected);
+
+        // 処理中のやり取りが終わった接続から閉じる
+        loop {
+            self.retain_connections(|client| {
+                if client.is_connected() && client.has_in_flight() {
+                    return true;
+                }
+                client.close();
+                false
+            });
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || self.lock_connections().is_empty() {
+                break;
+            }
+            self.wait_ready(Interest::Read, None, Some(remaining.min(SHUTDOWN_POLL_INTERVAL)))?;
+        }
+
+        for client in self.connections() {
+            client.close();
+        }
+        self.retain_connections(|_| false);
         Ok(())
     }
 
     /// クライアントからの接続イベントをポーリングします。
     ///
-    /// 非ブロッキングで接続をチェックし、接続があればクライアントを返します。
+    /// 非ブロッキングで接続をチェックし、ハンドシェイクが完了した接続を[`Event::ConnectionAccepted`]として返します。
+    /// ハンドシェイクは待たずに読み込める分ずつ進めるため、応答しないクライアントがいてもブロックしません。
+    ///
+    /// 登録済みの接続が切断された場合は[`Event::Disconnected`]を返します。
+    /// ハンドシェイク中の接続で発生したエラーは、接続IDを持たないイベントとして返され、その接続は閉じられます。
+    /// プロトコル違反やタイムアウト（[`ProtocolErrorKind::HandshakeTimeout`]）は[`Event::ProtocolError`]に、
+    /// それ以外のエラーは[`Event::Disconnected`]になります。
     /// タイムアウト時間内にイベントがなければNoneを返します。
     ///
     /// # エラー
-    /// 接続の受け入れに失敗した場合にエラーを返します。
+    /// リスナーでの接続の受け入れに失敗した場合にエラーを返します。
     pub fn poll_event(&mut self) -> Result<Option<Event<Client>>> {
-        self.listener.set_nonblocking(ListenerNonblockingMode::Accept)?;
-        let start = std::time::Instant::now();
+        self.next_event(self.timeout)
+    }
+
+    /// 接続の受け入れと切断の検出を、最大`timeout`まで待って1つのイベントを返します。
+    pub(crate) fn next_event(&mut self, timeout: Duration) -> Result<Option<Event<Client>>> {
+        let deadline = Instant::now() + timeout;
         loop {
-            match self.listener.accept() {
-                Ok(stream) => {
-                    let client: Client = stream.into();
-                    self.event_handler.notify(Event::<Client>::ConnectionAccepted(client.clone()));
-                    self.listener.set_nonblocking(ListenerNonblockingMode::Neither)?;
-                    return Ok(Some(Event::ConnectionAccepted(client)));
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    if start.elapsed() >= self.timeout {
-                        self.listener.set_nonblocking(ListenerNonblockingMode::Neither)?;
-                        return Ok(None);
+            self.retain_connections(Client::is_connected);
+            if let Some(event) = self.next_pending() {
+                return Ok(Some(event));
+            }
+
+            if let Some(listener) = &self.listener {
+                listener.set_nonblocking(ListenerNonblockingMode::Accept)?;
+                let accepted = listener.accept();
+                listener.set_nonblocking(ListenerNonblockingMode::Neither)?;
+                match accepted {
+                    Ok(stream) => {
+                        if let Some(event) = self.begin_handshake(stream) {
+                            return Ok(Some(event));
+                        }
                     }
-                    std::thread::sleep(Duration::from_millis(10));
-                    continue;
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
                 }
+            }
+            if let Some(event) = self.advance_handshakes() {
+                return Ok(Some(event));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            // 新しい接続か、ハンドシェイクの続き、登録済みの接続の切断を待つ
+            self.wait_ready(Interest::Hangup, None, Some(remaining))?;
+        }
+    }
+
+    /// 受け入れたストリームにハンドシェイクメッセージを送信し、相手の応答を待つ接続として保持します。
+    ///
+    /// ハンドシェイクを行わない設定では、すぐに登録して[`Event::ConnectionAccepted`]を返します。
+    /// 送信に失敗した場合は、その失敗を表すイベントを返します。
+    fn begin_handshake(&mut self, stream: LocalSocketStream) -> Option<Event<Client>> {
+        let mut client = Client::with_config(stream, self.config.clone());
+        match client.send_hello() {
+            Ok(true) => {
+                self.handshakes.push(Handshake {
+                    client,
+                    hello: [0; HELLO_LEN],
+                    filled: 0,
+                    deadline: Instant::now() + self.config.get_handshake_timeout(),
+                });
+                None
+            }
+            Ok(false) => Some(Event::ConnectionAccepted(self.register(client))),
+            Err(e) => Some(handshake_failed(e)),
+        }
+    }
+
+    /// ハンドシェイク中の接続から相手のハンドシェイクメッセージを読み込み、最初に決着した接続のイベントを返します。
+    ///
+    /// 完了した接続は登録して[`Event::ConnectionAccepted`]を返します。
+    /// 失敗またはタイムアウトした接続は閉じて、その失敗を表すイベントを返します。
+    fn advance_handshakes(&mut self) -> Option<Event<Client>> {
+        let now = Instant::now();
+        for index in 0..self.handshakes.len() {
+            let handshake = &mut self.handshakes[index];
+            let result = match handshake.client.read_hello(&mut handshake.hello, &mut handshake.filled) {
+                Ok(false) if handshake.deadline > now => continue,
+                Ok(false) => Err(ProtocolError::new(
+                    ProtocolErrorKind::HandshakeTimeout,
+                    "Client did not complete the handshake in time",
+                )
+                .into()),
+                Ok(true) => handshake.client.finish_handshake(&handshake.hello),
+                Err(e) => Err(e),
+            };
+            let Handshake { client, .. } = self.handshakes.swap_remove(index);
+            return Some(match result {
+                Ok(()) => Event::ConnectionAccepted(self.register(client)),
                 Err(e) => {
-                    self.listener.set_nonblocking(ListenerNonblockingMode::Neither)?;
-                    return Err(e);
+                    client.close();
+                    handshake_failed(e)
                 }
-            }
+            });
         }
+        None
     }
 
-    /// クライアントからの接続を受け入れます。
+    /// クライアントからの接続を受け入れ、ハンドシェイクを行います。
+    ///
+    /// 返されるクライアントは登録された接続のクローンです。ドロップしても接続は閉じられないため、
+    /// 接続を閉じる場合は[`disconnect`](Server::disconnect)を使用します。
     ///
     /// # エラー
-    /// 接続の受け入れに失敗した場合にエラーを返します。
+    /// 接続の受け入れに失敗した場合や、クライアントとのハンドシェイクに失敗した場合、
+    /// サーバーが終了している場合にエラーを返します。
     pub fn accept(&mut self) -> Result<Client> {
-        let stream = self.listener.accept()?;
-        let client: Client = stream.into();
-        self.event_handler.notify(Event::<Client>::ConnectionAccepted(client.clone()));
-        Ok(client)
+        let listener = self
+            .listener
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Server has been shut down"))?;
+        let stream = listener.accept()?;
+        self.establish(stream)
+    }
+
+    /// 接続中のクライアントの接続IDを、受け入れた順に返します。
+    ///
+    /// 切断が検出されたクライアントは登録から外され、結果に含まれません。
+    pub fn clients(&self) -> Vec<ConnectionId> {
+        self.retain_connections(Client::is_connected);
+        self.lock_connections().keys().copied().collect()
+    }
+
+    /// 登録中のすべてのクライアントを、受け入れた順に返します。
+    pub(crate) fn connections(&self) -> Vec<Client> {
+        self.lock_connections().values().cloned().collect()
+    }
+
+    /// 指定された接続IDのクライアントを返します。
+    ///
+    /// 返されるクライアントは登録された接続のクローンです。登録されていない場合はNoneを返します。
+    pub fn client(&self, id: ConnectionId) -> Option<Client> {
+        self.lock_connections().get(&id).cloned()
+    }
+
+    /// 指定された接続IDの接続を閉じ、登録から外します。
+    ///
+    /// 相手がgoodbyeフレームに対応していれば終了を通知してから閉じます（[`Client::stop`]を参照）。
+    /// 切断はリスナーに通知され、[`poll_event`](Server::poll_event)でも[`Event::Disconnected`]として返されます。
+    ///
+    /// # エラー
+    /// 接続IDが登録されていない場合や、goodbyeフレームの送信に失敗した場合にエラーを返します。
+    pub fn disconnect(&self, id: ConnectionId) -> Result<()> {
+        let mut client = self.client(id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No connection with id {}", id))
+        })?;
+        let result = client.stop();
+        self.retain_connections(|client| client.get_connection_id() != Some(id));
+        result
+    }
+
+    /// 指定された接続IDのクライアントにメッセージを送信します。
+    ///
+    /// 切断により送信に失敗したクライアントは登録から外されます。
+    ///
+    /// # エラー
+    /// 接続IDが登録されていない場合や、メッセージのシリアライズまたは送信に失敗した場合にエラーを返します。
+    pub fn send_to<T: Serialize>(&self, id: ConnectionId, message: &T) -> Result<()> {
+        let client = self.client(id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No connection with id {}", id))
+        })?;
+        let result = client.send(message);
+        if result.is_err() {
+            self.retain_connections(|client| client.get_disconnect_reason().is_none());
+        }
+        result
+    }
+
+    /// 接続中のすべてのクライアントにメッセージを送信します。
+    ///
+    /// メッセージは1回だけエンコードされます。切断により送信に失敗したクライアントは
+    /// 登録から外され、残りのクライアントへの送信は継続されます。
+    /// 送信できたクライアントの数を返します。
+    ///
+    /// 送信は登録済みの接続の一覧を取得してロックを解放してから行うため、読み込まない相手への送信で待っている間も、
+    /// 他のスレッドから接続の一覧の取得や[`disconnect`](Server::disconnect)などの操作を行えます。
+    ///
+    /// # エラー
+    /// メッセージのシリアライズに失敗した場合や、フレームが最大サイズを超える場合にエラーを返します。
+    pub fn broadcast<T: Serialize>(&self, message: &T) -> Result<usize> {
+        let frame = Frame::data(self.config.get_codec().encode(message)?);
+        let bytes = protocol::encode_frame(&frame, &self.config)?;
+        Ok(self.write_to_each(&bytes, |_| true))
+    }
+
+    /// `select`がtrueを返した登録済みの接続に、エンコード済みのフレームを送信します。
+    ///
+    /// 登録のロックを保持したまま送信しないよう、接続の一覧を取得してから送信し、
+    /// 送信中に切断された接続を最後にまとめて登録から外します。送信できた接続の数を返します。
+    pub(crate) fn write_to_each(&self, bytes: &[u8], mut select: impl FnMut(&Client) -> bool) -> usize {
+        let delivered = self
+            .connections()
+            .iter()
+            .filter(|client| select(client) && client.write_bytes(bytes).is_ok())
+            .count();
+        self.retain_connections(|client| client.get_disconnect_reason().is_none());
+        delivered
+    }
+
+    /// 受け入れたストリームとハンドシェイクを行い、クライアントを生成して登録します。
+    fn establish(&mut self, stream: LocalSocketStream) -> Result<Client> {
+        let mut client = Client::with_config(stream, self.config.clone());
+        client.handshake()?;
+        Ok(self.register(client))
+    }
+
+    /// ハンドシェイクが完了したクライアントに接続IDを割り当てて登録します。
+    fn register(&mut self, mut client: Client) -> Client {
+        let id = ConnectionId::new(self.next_id);
+        self.next_id += 1;
+        client.set_connection_id(id);
+        client.set_event_handler(self.event_handler.clone());
+        self.lock_connections().insert(id, client.clone());
+        self.event_handler.notify(&Event::<()>::ConnectionAccepted(client.clone()));
+        client
     }
 
     /// 現在のタイムアウト時間を取得します。
@@ -105,16 +453,278 @@ impl Server {
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.timeout = timeout;
     }
+
+    /// [`run`](Server::run)を別のスレッドから終了させるためのハンドルを返します。
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// このサーバーのイベントを通知するハンドラーを返します。
+    ///
+    /// ハンドラーは受け入れたすべての接続に引き継がれるため、登録したリスナーは
+    /// 各接続のメッセージの送受信や切断の際にも呼び出されます。
+    pub fn event_handler(&self) -> &EventHandler {
+        &self.event_handler
+    }
+
+    /// 受け入れた接続に適用されるプロトコルの設定を取得します。
+    pub fn get_config(&self) -> &ProtocolConfig {
+        &self.config
+    }
+
+    /// `keep`がfalseを返した接続を登録から外し、それぞれの切断をリスナーに通知してイベントを記録します。
+    ///
+    /// 記録したイベントは[`poll_event`](Server::poll_event)で順に返されます。
+    /// `keep`は登録のロックを保持したまま呼び出されるため、送信などのブロックする操作を行ってはいけません。
+    pub(crate) fn retain_connections(&self, mut keep: impl FnMut(&Client) -> bool) {
+        let mut disconnected = Vec::new();
+        self.lock_connections().retain(|_, client| {
+            if keep(client) {
+                return true;
+            }
+            disconnected.push(client.clone());
+            false
+        });
+        for client in disconnected {
+            let reason = client.get_disconnect_reason().unwrap_or(DisconnectReason::Reset);
+            client.notify_disconnected(reason);
+            self.pending
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push_back(Event::Disconnected {
+                    connection: client.get_connection_id(),
+                    reason,
+                });
+        }
+    }
+
+    /// リスナーへの接続、または登録済みの接続が`interest`の状態になるまで待ちます。
+    ///
+    /// ハンドシェイク中の接続にデータが届いた場合と、そのタイムアウトでも起床します。
+    /// `shutdown`が指定された場合は、終了の要求でも起床します。`timeout`がNoneの場合は無期限に待ちます。
+    pub(crate) fn wait_ready(
+        &self,
+        interest: Interest,
+        shutdown: Option<&ShutdownHandle>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.wait_ready_with(interest, &[], shutdown, timeout)
+    }
+
+    /// [`wait_ready`](Server::wait_ready)に加えて、`readable`の接続にデータが届いた場合にも起床します（Unix用）。
+    #[cfg(unix)]
+    pub(crate) fn wait_ready_with(
+        &self,
+        interest: Interest,
+        readable: &[Client],
+        shutdown: Option<&ShutdownHandle>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        use std::os::fd::AsFd;
+
+        let connections = self.connections();
+        let mut fds = Vec::with_capacity(connections.len() + readable.len() + self.handshakes.len() + 2);
+        if let Some(LocalSocketListener::UdSocket(listener)) = self.listener.as_deref() {
+            fds.push(readiness::pollfd(listener.as_fd(), Interest::Read));
+        }
+        fds.extend(connections.iter().map(|client| client.pollfd(interest)));
+        fds.extend(readable.iter().map(|client| client.pollfd(Interest::Read)));
+        fds.extend(self.handshakes.iter().map(|handshake| handshake.client.pollfd(Interest::Read)));
+        fds.extend(shutdown.and_then(ShutdownHandle::pollfd));
+        readiness::poll(&mut fds, self.handshake_timeout(timeout)).map(|_| ())
+    }
+
+    /// [`wait_ready`](Server::wait_ready)に加えて、`readable`の接続にデータが届いた場合にも起床します（非Unix用）。
+    ///
+    /// レディネス通知を利用できないため、一定時間待ってから呼び出し元に再確認させます。
+    #[cfg(not(unix))]
+    pub(crate) fn wait_ready_with(
+        &self,
+        _interest: Interest,
+        _readable: &[Client],
+        _shutdown: Option<&ShutdownHandle>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        readiness::sleep(self.handshake_timeout(timeout));
+        Ok(())
+    }
+
+    /// 待機の時間を、ハンドシェイク中の接続のうち最も早いタイムアウトまでに制限します。
+    fn handshake_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let now = Instant::now();
+        self.handshakes
+            .iter()
+            .map(|handshake| handshake.deadline.saturating_duration_since(now))
+            .chain(timeout)
+            .min()
+    }
+
+    /// 記録済みのイベントがあれば1つ取り出します。
+    fn next_pending(&self) -> Option<Event<Client>> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop_front()
+    }
+
+    /// 接続の登録簿をロックします。
+    fn lock_connections(&self) -> MutexGuard<'_, BTreeMap<ConnectionId, Client>> {
+        self.connections.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 }
 
-/// プラットフォームに応じたソケット名を生成します（Windows用）。
-#[cfg(target_os = "windows")]
-fn socket_name(name: &str) -> String {
-    format!(r"\\.\pipe\{}", name)
+/// ハンドシェイク中の接続で発生したエラーを、接続IDを持たないイベントに変換します。
+///
+/// 切断やプロトコル違反に当たらないエラーも、接続の異常終了として扱います。
+fn handshake_failed(error: io::Error) -> Event<Client> {
+    Event::from_error(error, None).unwrap_or(Event::Disconnected {
+        connection: None,
+        reason: DisconnectReason::Reset,
+    })
 }
 
-/// プラットフォームに応じたソケット名を生成します（非Windows用）。
-#[cfg(not(target_os = "windows"))]
-fn socket_name(name: &str) -> String {
-    format!("/tmp/{}", name)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interprocess::local_socket::traits::Stream;
+    use std::thread;
+
+    #[test]
+    fn disconnect_closes_and_unregisters_connection() {
+        let name = format!("instance-pipe-test-disconnect-{}", std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let connecting = thread::spawn(move || Client::start(&name));
+        let id = server.accept().unwrap().get_connection_id().unwrap();
+        let mut client = connecting.join().unwrap().unwrap();
+
+        server.disconnect(id).unwrap();
+        assert!(server.clients().is_empty());
+        assert!(matches!(
+            server.poll_event().unwrap(),
+            Some(Event::Disconnected { connection: Some(closed), reason: DisconnectReason::Shutdown }) if closed == id
+        ));
+        assert_eq!(server.disconnect(id).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        client.set_timeout(Duration::from_secs(5));
+        assert!(matches!(client.poll_event::<()>().unwrap(), Some(Event::Goodbye { .. })));
+        assert!(matches!(
+            client.poll_event::<()>().unwrap(),
+            Some(Event::Disconnected { reason: DisconnectReason::Shutdown, .. })
+        ));
+    }
+
+    #[test]
+    fn silent_handshake_times_out_without_blocking_other_clients() {
+        let name = format!("instance-pipe-test-handshake-{}", std::process::id());
+        let config = ProtocolConfig::new().handshake_timeout(Duration::from_millis(200));
+        let mut server = Server::start_with(&name, config).unwrap();
+        server.set_timeout(Duration::from_secs(5));
+
+        // ハンドシェイクメッセージを送信しない接続
+        let _silent = LocalSocketStream::connect(name::resolve(&name).unwrap()).unwrap();
+        let connecting = thread::spawn(move || Client::start(&name));
+        let started = Instant::now();
+        assert!(matches!(server.poll_event().unwrap(), Some(Event::ConnectionAccepted(_))));
+        assert!(started.elapsed() < Duration::from_millis(200));
+        let _client = connecting.join().unwrap().unwrap();
+
+        match server.poll_event().unwrap() {
+            Some(Event::ProtocolError(e)) => assert_eq!(e.kind(), ProtocolErrorKind::HandshakeTimeout),
+            _ => panic!("expected a handshake timeout"),
+        }
+        assert_eq!(server.clients().len(), 1);
+    }
+
+    #[test]
+    fn shutdown_closes_once_requests_are_answered() {
+        let name = format!("instance-pipe-test-shutdown-{}", std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let connecting = thread::spawn(move || Client::start(&name));
+        let mut peer = server.accept().unwrap();
+        let mut client = connecting.join().unwrap().unwrap();
+
+        let calling = {
+            let client = client.clone();
+            thread::spawn(move || client.call::<u32, u32>(&20, Duration::from_secs(5)))
+        };
+        peer.set_timeout(Duration::from_secs(5));
+        let request = peer.poll_request::<u32>().unwrap().unwrap();
+        let responding = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            request.respond(&(request.request() + 1))
+        });
+
+        let started = Instant::now();
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        responding.join().unwrap().unwrap();
+        assert_eq!(calling.join().unwrap().unwrap(), 21);
+
+        client.set_timeout(Duration::from_secs(5));
+        assert!(matches!(client.poll_event::<()>().unwrap(), Some(Event::Goodbye { .. })));
+        assert!(matches!(
+            client.poll_event::<()>().unwrap(),
+            Some(Event::Disconnected { reason: DisconnectReason::Shutdown, .. })
+        ));
+    }
+
+    #[test]
+    fn shutdown_closes_peers_that_never_read_within_grace() {
+        let name = format!("instance-pipe-test-stalled-{}", std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let mut accept = || {
+            let name = name.clone();
+            let connecting = thread::spawn(move || Client::start(&name));
+            let accepted = server.accept().unwrap();
+            (accepted, connecting.join().unwrap().unwrap())
+        };
+        // 相手が読み込まないまま、送信バッファを埋めておく
+        let (saturated, _saturated_peer) = accept();
+        let chunk = protocol::encode_frame(&Frame::data(vec![0; 64 * 1024]), saturated.get_config()).unwrap();
+        while saturated
+            .write_bytes_until(&chunk, Some(Instant::now() + Duration::from_millis(20)))
+            .is_ok()
+        {}
+        // 別のスレッドが送信の途中で止まったまま、送信のロックを保持している
+        let (blocked, _blocked_peer) = accept();
+        let writing = thread::spawn(move || while blocked.send(&vec![0u8; 64 * 1024]).is_ok() {});
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        server.shutdown(Duration::from_millis(100)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(server.clients().is_empty());
+        assert_eq!(saturated.get_disconnect_reason(), Some(DisconnectReason::Shutdown));
+        writing.join().unwrap();
+    }
+
+    #[test]
+    fn broadcast_to_stalled_client_does_not_freeze_registry() {
+        let name = format!("instance-pipe-test-broadcast-{}", std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let mut accept = || {
+            let name = name.clone();
+            let connecting = thread::spawn(move || Client::start(&name));
+            let id = server.accept().unwrap().get_connection_id().unwrap();
+            (id, connecting.join().unwrap().unwrap())
+        };
+        let (stalled, _stalled_peer) = accept();
+        let (_, reader) = accept();
+        let reading = thread::spawn(move || reader.recv::<Vec<u8>>());
+
+        // 読み込まない相手の送信バッファを超える大きさのメッセージを配信する
+        let server = std::sync::Arc::new(server);
+        let broadcasting = {
+            let server = server.clone();
+            thread::spawn(move || server.broadcast(&vec![0u8; 4 * 1024 * 1024]))
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        assert_eq!(server.clients().len(), 2);
+        server.disconnect(stalled).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(broadcasting.join().unwrap().unwrap(), 1);
+        assert_eq!(reading.join().unwrap().unwrap().len(), 4 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+/// サービスのクライアントスタブが、レスポンスを待つ既定の最大時間。
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 型付きのメソッドを持つサービスを定義し、クライアントスタブとサーバー側のディスパッチャーを生成します。
+///
+/// 指定した名前のモジュールに、次の項目を生成します。
+///
+/// - `Service`: サーバー側で実装するトレイト。各メソッドは`&self`と引数を受け取り、レスポンスを返します。
+/// - `Request`: 各メソッドの呼び出しを表すリクエストの列挙型。メソッド名がそのままバリアント名になります。
+/// - `Client`: [`Client::call`](crate::Client::call)で各メソッドを呼び出すクライアントスタブ。
+/// - `dispatch`: 受信した1つのリクエストを`Service`の実装に渡し、戻り値をレスポンスとして返す関数。
+/// - `serve`: 接続が閉じられるまでリクエストを受信してディスパッチし続ける関数。
+///
+/// 引数と戻り値の型は`serde`でシリアライズできる必要があります。
+/// 生成されるコードはこのクレートが再エクスポートする`serde`を参照するため、
+/// 呼び出し側のクレートが`serde`に依存する必要はありません。
+///
+/// # 例
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// instance_pipe::service! {
+///     /// 計算を行うサービス。
+///     pub mod calculator {
+///         /// 2つの数値を足し合わせます。
+///         fn add(a: i32, b: i32) -> i32;
+///         /// 名前に対する挨拶を返します。
+///         fn greet(name: String) -> String;
+///     }
+/// }
+///
+/// struct Calculator;
+///
+/// impl calculator::Service for Calculator {
+///     fn add(&self, a: i32, b: i32) -> i32 {
+///         a + b
+///     }
+///
+///     fn greet(&self, name: String) -> String {
+///         format!("Hello, {}!", name)
+///     }
+/// }
+///
+/// // サーバー側: 受け入れた接続ごとに呼び出す
+/// let mut server = instance_pipe::Server::start("calculator")?;
+/// let connection = server.accept()?;
+/// calculator::serve(&Calculator, &connection)?;
+///
+/// // クライアント側
+/// let calculator = calculator::Client::new(instance_pipe::Client::start("calculator")?);
+/// assert_eq!(calculator.add(1, 2)?, 3);
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! service {
+    (
+        $(#[$module_attr:meta])*
+        $vis:vis mod $module:ident {
+            $(
+                $(#[$method_attr:meta])*
+                fn $method:ident($($arg:ident: $arg_ty:ty),* $(,)?) -> $ret:ty;
+            )*
+        }
+    ) => {
+        $(#[$module_attr])*
+        $vis mod $module {
+            #[allow(unused_imports)]
+            use super::*;
+            #[doc(hidden)]
+            use $crate::__serde as __instance_pipe_serde;
+
+            /// サーバー側で実装するサービスのトレイト。
+            pub trait Service {
+                $(
+                    $(#[$method_attr])*
+                    fn $method(&self, $($arg: $arg_ty),*) -> $ret;
+                )*
+            }
+
+            /// サービスのメソッド呼び出しを表すリクエスト。
+            #[allow(non_camel_case_types)]
+            #[derive(__instance_pipe_serde::Serialize, __instance_pipe_serde::Deserialize, Debug, Clone)]
+            #[serde(crate = "self::__instance_pipe_serde")]
+            pub enum Request {
+                $(
+                    $(#[$method_attr])*
+                    $method { $($arg: $arg_ty),* },
+                )*
+            }
+
+            /// サービスのメソッドを相手に呼び出すクライアントスタブ。
+            #[derive(Clone)]
+            pub struct Client {
+                client: $crate::Client,
+                timeout: ::std::time::Duration,
+            }
+
+            impl Client {
+                /// 接続済みのクライアントからスタブを作成します。
+                pub fn new(client: $crate::Client) -> Self {
+                    Self {
+                        client,
+                        timeout: $crate::instance::service::DEFAULT_CALL_TIMEOUT,
+                    }
+                }
+
+                /// レスポンスを待つ最大時間を設定します。
+                pub fn with_timeout(mut self, timeout: ::std::time::Duration) -> Self {
+                    self.timeout = timeout;
+                    self
+                }
+
+                /// 内部のクライアントへの参照を返します。
+                pub fn client(&self) -> &$crate::Client {
+                    &self.client
+                }
+
+                $(
+                    $(#[$method_attr])*
+                    pub fn $method(&self, $($arg: $arg_ty),*) -> ::std::io::Result<$ret> {
+                        self.client.call(&Request::$method { $($arg),* }, self.timeout)
+                    }
+                )*
+            }
+
+            /// 受信したリクエストを`service`に渡し、戻り値を呼び出し元に返します。
+            ///
+            /// # エラー
+            /// レスポンスの送信に失敗した場合にエラーを返します。
+            pub fn dispatch<S: Service + ?Sized>(
+                service: &S,
+                request: $crate::RpcRequest<Request>,
+            ) -> ::std::io::Result<()> {
+                let (request, responder) = request.into_parts();
+                match request {
+                    $(
+                        Request::$method { $($arg),* } => responder.respond(&service.$method($($arg),*)),
+                    )*
+                }
+            }
+
+            /// 相手が接続を閉じるまでリクエストを受信し、`service`にディスパッチします。
+            ///
+            /// デコードできないリクエスト（このサービスが知らないメソッドなど）は、
+            /// 呼び出し元にエラーを返したうえで読み飛ばします。
+            ///
+            /// # エラー
+            /// 接続が閉じられる前に受信または送信に失敗した場合にエラーを返します。
+            pub fn serve<S: Service + ?Sized>(service: &S, client: &$crate::Client) -> ::std::io::Result<()> {
+                loop {
+                    match client.recv_request::<Request>() {
+                        Ok(request) => dispatch(service, request)?,
+                        Err(e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                        Err(e)
+                            if e.kind() == ::std::io::ErrorKind::InvalidData
+                                && $crate::ProtocolError::kind_of(&e).is_none() => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Server;
+    use std::thread;
+    use std::time::Duration;
+
+    crate::service! {
+        /// テスト用の計算サービス。
+        mod calculator {
+            /// 2つの数値を足し合わせます。
+            fn add(a: i32, b: i32) -> i32;
+            /// 名前に対する挨拶を返します。
+            fn greet(name: String) -> String;
+        }
+    }
+
+    struct Calculator;
+
+    impl calculator::Service for Calculator {
+        fn add(&self, a: i32, b: i32) -> i32 {
+            a + b
+        }
+
+        fn greet(&self, name: String) -> String {
+            format!("Hello, {}!", name)
+        }
+    }
+
+    #[test]
+    fn generated_stub_calls_service() {
+        let name = format!("instance-pipe-test-service-{}", std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let connecting = thread::spawn(move || crate::Client::start(&name));
+        let connection = server.accept().unwrap();
+        let serving = thread::spawn(move || calculator::serve(&Calculator, &connection));
+
+        let calculator = calculator::Client::new(connecting.join().unwrap().unwrap())
+            .with_timeout(Duration::from_secs(5));
+        assert!(calculator.client().is_connected());
+        assert_eq!(calculator.add(1, 2).unwrap(), 3);
+        assert_eq!(calculator.greet("pipe".to_string()).unwrap(), "Hello, pipe!");
+
+        drop(calculator);
+        serving.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,558 @@
+use crate::instance::event::{DisconnectReason, Event};
+use crate::instance::name::InstanceName;
+use crate::instance::readiness::Interest;
+use crate::instance::server::ConnectionId;
+use crate::protocol::codec::Codec;
+use crate::protocol::{FrameKind, ProtocolConfig, ProtocolError, ProtocolErrorKind};
+use crate::{Client, Server};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, ErrorKind, Result};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// プライマリへの接続を再試行する最大回数。
+const ACQUIRE_ATTEMPTS: u32 = 10;
+/// 再試行の間隔。
+const ACQUIRE_RETRY_DELAY: Duration = Duration::from_millis(20);
+/// 接続したセカンダリからアクティベーションを受信するまでの待ち時間。
+const ACTIVATION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 単一インスタンス制御の結果。
+///
+/// 最初に起動したプロセスは`Primary`としてサーバーを保持し、
+/// 以降に起動したプロセスは`Secondary`としてプライマリに接続したクライアントを保持します。
+///
+/// # セキュリティ
+/// インスタンス名はアプリケーションIDなどから導出されるため、他のユーザーも同じ名前を計算できます。
+/// Linuxの名前空間ソケットにはファイルのパーミッションがなく、ファイルシステムソケットも共有の`/tmp`に作成されるため、
+/// 他のユーザーが先に同じ名前で待ち受けることを防ぐことはできません。
+///
+/// そのため、ユーザーごとの名前（[`InstanceName::per_user`]、既定で有効）を使用する場合は、
+/// 接続の両端で相手の資格情報を検証し（[`ProtocolConfig::require_same_user`]）、異なるユーザーとの接続を拒否します。
+/// これにより、他のユーザーがプライマリを装って起動情報（引数・カレントディレクトリ・環境変数）を受け取ることや、
+/// セカンダリを装ってプライマリに起動情報を送り込むことを防ぎます。
+/// 一方、他のユーザーが先に名前を占有した場合は、
+/// [`ProtocolErrorKind::UnauthorizedPeer`]のエラーで取得に失敗するため、サービス妨害は防げません。
+/// 同じユーザーで動作するプロセスはすべて信頼されます。
+pub enum SingleInstance {
+    /// このプロセスがプライマリインスタンスであることを示します。
+    Primary(Primary),
+    /// 既にプライマリインスタンスが存在することを示します。
+    Secondary(Secondary),
+}
+
+/// プライマリインスタンス。他のインスタンスからの接続を待ち受けます。
+pub struct Primary {
+    server: Server,
+    /// 接続したが、まだ起動情報を受信していないセカンダリ。
+    waiting: Vec<WaitingSecondary>,
+}
+
+/// 起動情報の受信を待っているセカンダリとの接続。
+struct WaitingSecondary {
+    client: Client,
+    deadline: Instant,
+}
+
+/// セカンダリインスタンス。プライマリインスタンスに接続済みのクライアントを保持します。
+pub struct Secondary {
+    client: Client,
+}
+
+/// プライマリインスタンスで発生したイベント。
+///
+/// 今後のバージョンでバリアントが追加される可能性があるため、`match`ではワイルドカードの腕が必要です。
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum PrimaryEvent {
+    /// セカンダリインスタンスが起動情報を転送しました。
+    Activated(Activation),
+    /// セカンダリインスタンスとの接続が切断されました。
+    Disconnected {
+        connection: Option<ConnectionId>,
+        reason: DisconnectReason,
+    },
+    /// セカンダリインスタンスがプロトコルに違反したフレームを送信しました。
+    ProtocolError(ProtocolError),
+}
+
+/// セカンダリインスタンスからプライマリインスタンスに転送される起動情報。
+///
+/// セカンダリは接続直後にこのメッセージを自動的に送信し、
+/// プライマリでは[`PrimaryEvent::Activated`]として受け取り、[`Activation::respond`]で結果を返します。
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Activation {
+    /// セカンダリインスタンスのコマンドライン引数（プログラム名を含む）。
+    pub args: Vec<String>,
+    /// セカンダリインスタンスのカレントディレクトリ。
+    pub cwd: PathBuf,
+    /// 許可リストに含まれ、かつセカンダリで設定されていた環境変数。
+    pub env: BTreeMap<String, String>,
+    /// 応答を返すためのセカンダリとの接続。プライマリで受信した場合のみ設定されます。
+    #[serde(skip)]
+    responder: Option<Client>,
+}
+
+/// プライマリインスタンスがセカンダリインスタンスに返すアクティベーションの結果。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ActivationResult {
+    /// プライマリが起動情報を受け入れたことを示します。
+    Accepted,
+    /// プライマリが起動情報を拒否したことを示します。
+    Refused(String),
+    /// セカンダリが指定された終了コードで終了すべきことを示します。
+    Exit(i32),
+}
+
+impl SingleInstance {
+    /// 指定されたアプリケーションIDでインスタンスの取得を試みます。
+    ///
+    /// サーバーの起動に成功した場合は`Primary`を、既に同名のサーバーが存在する場合は
+    /// そのサーバーに接続して`Secondary`を返します。
+    /// 複数のプロセスが同時に起動した場合でも、どちらか一方のみが`Primary`になります。
+    /// クラッシュしたプライマリが残したソケットファイルは検出して削除します。
+    ///
+    /// サーバーおよびクライアントの名前は、`app_id`と現在のユーザーから[`InstanceName`]で導出されます。
+    /// `Secondary`になった場合は、コマンドライン引数とカレントディレクトリがプライマリに転送されます。
+    ///
+    /// # 引数
+    /// - `app_id`: アプリケーションを識別する名前。
+    ///
+    /// # エラー
+    /// 名前の導出に失敗した場合や、サーバーの起動とプライマリへの接続の両方に失敗した場合にエラーを返します。
+    pub fn acquire(app_id: &str) -> Result<Self> {
+        Self::acquire_with(&InstanceName::new(app_id), &[])
+    }
+
+    /// 指定されたインスタンス名でインスタンスの取得を試みます。
+    ///
+    /// ユーザーごとだけでなく、セッションや作業ディレクトリごとにインスタンスを区別したい場合に使用します。
+    ///
+    /// # 引数
+    /// - `name`: インスタンス名を導出するビルダー。
+    /// - `forward_env`: `Secondary`になった場合にプライマリへ転送する環境変数名の許可リスト。
+    ///
+    /// # エラー
+    /// 名前の導出に失敗した場合や、サーバーの起動とプライマリへの接続の両方に失敗した場合、
+    /// または起動情報の転送に失敗した場合にエラーを返します。
+    pub fn acquire_with(name: &InstanceName, forward_env: &[&str]) -> Result<Self> {
+        let config = ProtocolConfig::new().require_same_user(name.get_per_user());
+        let name = name.build()?;
+        let name = name.as_str();
+        for _ in 0..ACQUIRE_ATTEMPTS {
+            match Server::start_with(name, config.clone()) {
+                Ok(server) => return Ok(Self::Primary(Primary::new(server))),
+                Err(e) if e.kind() == ErrorKind::AddrInUse => {}
+                Err(e) => return Err(e),
+            }
+            match Client::start_with(name, config.clone()) {
+                Ok(client) => return Secondary::forward(client, forward_env).map(Self::Secondary),
+                // プライマリがlisten前、または終了直後のため再試行する
+                Err(e) if is_not_listening(&e) => thread::sleep(ACQUIRE_RETRY_DELAY),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // 待ち受けているプライマリに一定時間接続できない場合は、最後にもう一度サーバーの起動を試みる
+        match Server::start_with(name, config.clone()) {
+            Ok(server) => Ok(Self::Primary(Primary::new(server))),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                Secondary::forward(Client::start_with(name, config)?, forward_env).map(Self::Secondary)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// このインスタンスがプライマリであるかどうかを返します。
+    pub fn is_primary(&self) -> bool {
+        matches!(self, Self::Primary(_))
+    }
+}
+
+impl Primary {
+    /// 待ち受けを開始したサーバーから`Primary`を作成します。
+    fn new(server: Server) -> Self {
+        Self {
+            server,
+            waiting: Vec::new(),
+        }
+    }
+
+    /// セカンダリインスタンスからの起動イベントをポーリングします。
+    ///
+    /// セカンダリが接続して起動情報を転送すると、[`PrimaryEvent::Activated`]として返します。
+    /// 起動情報の受信は待たずに読み込める分ずつ進めるため、応答しないセカンダリがいてもブロックしません。
+    /// タイムアウト時間内にイベントがなければNoneを返します。
+    ///
+    /// セカンダリが1秒以内に起動情報を送信しない場合や、不正な起動情報を送信した場合は
+    /// [`PrimaryEvent::ProtocolError`]を返して接続を閉じます。
+    /// 閉じた接続や、相手が閉じた接続は[`PrimaryEvent::Disconnected`]として返します。
+    ///
+    /// # エラー
+    /// リスナーでの接続の受け入れに失敗した場合にエラーを返します。
+    pub fn poll_event(&mut self) -> Result<Option<PrimaryEvent>> {
+        let deadline = Instant::now() + self.server.get_timeout();
+        loop {
+            if let Some(event) = self.advance_activations() {
+                return Ok(Some(event));
+            }
+            match self.server.next_event(Duration::ZERO)? {
+                Some(Event::ConnectionAccepted(client)) => {
+                    self.waiting.push(WaitingSecondary {
+                        client,
+                        deadline: Instant::now() + ACTIVATION_TIMEOUT,
+                    });
+                    continue;
+                }
+                Some(Event::Disconnected { connection, reason }) => {
+                    self.waiting.retain(|secondary| secondary.client.get_connection_id() != connection);
+                    return Ok(Some(PrimaryEvent::Disconnected { connection, reason }));
+                }
+                Some(Event::ProtocolError(e)) => return Ok(Some(PrimaryEvent::ProtocolError(e))),
+                Some(_) => continue,
+                None => {}
+            }
+
+            let now = Instant::now();
+            let remaining = deadline.saturating_duration_since(now);
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            // 新しい接続か、起動情報の到着、そのタイムアウトを待つ
+            let timeout = self
+                .waiting
+                .iter()
+                .map(|secondary| secondary.deadline.saturating_duration_since(now))
+                .fold(remaining, Duration::min);
+            let readable: Vec<Client> = self.waiting.iter().map(|secondary| secondary.client.clone()).collect();
+            self.server.wait_ready_with(Interest::Hangup, &readable, None, Some(timeout))?;
+        }
+    }
+
+    /// 起動情報を待っている接続から受信を試み、最初に決着した接続のイベントを返します。
+    ///
+    /// 切断された接続は待機から外すのみで、切断はサーバーのイベントとして返されます。
+    fn advance_activations(&mut self) -> Option<PrimaryEvent> {
+        let now = Instant::now();
+        let mut index = 0;
+        while index < self.waiting.len() {
+            let secondary = &self.waiting[index];
+            let error = match secondary.client.try_next_frame(FrameKind::Data) {
+                Ok(Some(frame)) => {
+                    let codec = secondary.client.get_config().get_codec();
+                    match codec.decode::<Activation>(&frame.payload) {
+                        Ok(mut activation) => {
+                            activation.responder = Some(self.waiting.swap_remove(index).client);
+                            return Some(PrimaryEvent::Activated(activation));
+                        }
+                        Err(e) => ProtocolError::new(
+                            ProtocolErrorKind::InvalidFrame,
+                            format!("Secondary instance sent a malformed activation: {}", e),
+                        ),
+                    }
+                }
+                Ok(None) if secondary.deadline > now => {
+                    index += 1;
+                    continue;
+                }
+                Ok(None) => ProtocolError::new(
+                    ProtocolErrorKind::HandshakeTimeout,
+                    "Secondary instance did not send its activation in time",
+                ),
+                Err(e) if DisconnectReason::from_error(&e).is_some() => {
+                    self.waiting.swap_remove(index);
+                    continue;
+                }
+                Err(e) => ProtocolError::from_io(&e)
+                    .unwrap_or_else(|| ProtocolError::new(ProtocolErrorKind::InvalidFrame, e.to_string())),
+            };
+            let secondary = self.waiting.swap_remove(index);
+            if let Some(id) = secondary.client.get_connection_id() {
+                // 切断はサーバーのイベントとして、次回以降のポーリングで返される
+                let _ = self.server.disconnect(id);
+            }
+            return Some(PrimaryEvent::ProtocolError(error));
+        }
+        None
+    }
+
+    /// セカンダリインスタンスの接続を受け入れ、転送された起動情報を返します。
+    ///
+    /// 起動情報が届くまで最大1秒待ちます。受信に失敗した接続は閉じられます。
+    ///
+    /// # エラー
+    /// 接続の受け入れに失敗した場合や、セカンダリが起動情報を送信しなかった場合にエラーを返します。
+    pub fn accept(&mut self) -> Result<Activation> {
+        let client = self.server.accept()?;
+        let id = client.get_connection_id();
+        receive_activation(client).inspect_err(|_| {
+            if let Some(id) = id {
+                let _ = self.server.disconnect(id);
+            }
+        })
+    }
+
+    /// 内部のサーバーへの参照を返します。
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// 内部のサーバーへの可変参照を返します。
+    pub fn server_mut(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    /// `Primary`を消費して内部のサーバーを返します。
+    pub fn into_server(self) -> Server {
+        self.server
+    }
+}
+
+impl Secondary {
+    /// 転送した起動情報に対するプライマリの応答を待ちます。
+    ///
+    /// 起動情報は[`SingleInstance::acquire`]の時点で転送済みのため、このメソッドは応答の受信のみを行います。
+    /// 受け取った結果の[`ActivationResult::exit_code`]をセカンダリの終了コードとして使用できます。
+    ///
+    /// # 引数
+    /// - `timeout`: 応答を待つ最大時間。
+    ///
+    /// # エラー
+    /// 応答の受信に失敗した場合や、タイムアウト時間内に応答がなかった場合にエラーを返します。
+    pub fn wait_for_result(&mut self, timeout: Duration) -> Result<ActivationResult> {
+        let previous = self.client.get_timeout();
+        self.client.set_timeout(timeout);
+        let event = self.client.poll_event::<ActivationResult>();
+        self.client.set_timeout(previous);
+        match event? {
+            Some(Event::MessageReceived(result)) => Ok(result),
+            Some(Event::Disconnected { reason, .. }) => Err(reason.into()),
+            Some(Event::ProtocolError(e)) => Err(e.into()),
+            _ => Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Primary instance did not reply to the activation",
+            )),
+        }
+    }
+
+    /// 現在のプロセスの起動情報をプライマリに送信し、`Secondary`を作成します。
+    fn forward(client: Client, forward_env: &[&str]) -> Result<Self> {
+        client.send(&Activation::capture(forward_env)?)?;
+        Ok(Self { client })
+    }
+
+    /// 内部のクライアントへの参照を返します。
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// 内部のクライアントへの可変参照を返します。
+    pub fn client_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// `Secondary`を消費して内部のクライアントを返します。
+    pub fn into_client(self) -> Client {
+        self.client
+    }
+}
+
+impl Activation {
+    /// 現在のプロセスの起動情報を取得します。
+    ///
+    /// # 引数
+    /// - `forward_env`: 取得する環境変数名の許可リスト。設定されていない変数は無視されます。
+    ///
+    /// # エラー
+    /// カレントディレクトリの取得に失敗した場合にエラーを返します。
+    pub fn capture(forward_env: &[&str]) -> Result<Self> {
+        Ok(Self {
+            args: std::env::args_os()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            cwd: std::env::current_dir()?,
+            env: forward_env
+                .iter()
+                .filter_map(|key| std::env::var(key).ok().map(|value| (key.to_string(), value)))
+                .collect(),
+            responder: None,
+        })
+    }
+
+    /// セカンダリインスタンスにアクティベーションの結果を返します。
+    ///
+    /// # 引数
+    /// - `result`: セカンダリに通知する結果。
+    ///
+    /// # エラー
+    /// プライマリで受信した起動情報でない場合や、送信に失敗した場合にエラーを返します。
+    pub fn respond(&self, result: &ActivationResult) -> Result<()> {
+        match &self.responder {
+            Some(client) => client.send(result),
+            None => Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "Activation was not received from a secondary instance",
+            )),
+        }
+    }
+}
+
+impl fmt::Debug for Activation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Activation")
+            .field("args", &self.args)
+            .field("cwd", &self.cwd)
+            .field("env", &self.env)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ActivationResult {
+    /// セカンダリインスタンスが使用すべき終了コードを返します。
+    ///
+    /// `Accepted`は`0`、`Refused`は`1`、`Exit`は指定されたコードになります。
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Accepted => 0,
+            Self::Refused(_) => 1,
+            Self::Exit(code) => *code,
+        }
+    }
+}
+
+/// 接続したセカンダリから起動情報を受信します。
+fn receive_activation(mut client: Client) -> Result<Activation> {
+    client.set_timeout(ACTIVATION_TIMEOUT);
+    match client.poll_event::<Activation>()? {
+        Some(Event::MessageReceived(mut activation)) => {
+            activation.responder = Some(client);
+            Ok(activation)
+        }
+        Some(Event::Disconnected { reason, .. }) => Err(reason.into()),
+        Some(Event::ProtocolError(e)) => Err(e.into()),
+        _ => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "Secondary instance did not send its activation",
+        )),
+    }
+}
+
+/// 接続先が存在しない、または待ち受けていないことを示すエラーかどうかを判定します。
+fn is_not_listening(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silent_secondary_is_reported_without_blocking_activations() {
+        let name = format!("instance-pipe-test-primary-{}", std::process::id());
+        let mut primary = Primary::new(Server::start(&name).unwrap());
+
+        // 起動情報を送信しないセカンダリと、通常のセカンダリを接続する
+        let silent = {
+            let name = name.clone();
+            thread::spawn(move || Client::start(&name))
+        };
+        let secondary = thread::spawn(move || {
+            let mut secondary = Secondary::forward(Client::start(&name)?, &[])?;
+            let result = secondary.wait_for_result(Duration::from_secs(5))?;
+            Ok::<_, io::Error>((secondary, result))
+        });
+
+        let started = Instant::now();
+        let activation = loop {
+            match primary.poll_event().unwrap() {
+                Some(PrimaryEvent::Activated(activation)) => break activation,
+                None => assert!(started.elapsed() < ACTIVATION_TIMEOUT),
+                Some(_) => panic!("unexpected event before the activation"),
+            }
+        };
+        activation.respond(&ActivationResult::Accepted).unwrap();
+        let (_secondary, result) = secondary.join().unwrap().unwrap();
+        assert_eq!(result, ActivationResult::Accepted);
+        let _silent = silent.join().unwrap().unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            match primary.poll_event().unwrap() {
+                Some(PrimaryEvent::ProtocolError(e)) => events.push(Some(e.kind())),
+                Some(PrimaryEvent::Disconnected { .. }) => events.push(None),
+                Some(_) => panic!("unexpected event for the silent secondary"),
+                None => assert!(started.elapsed() < ACTIVATION_TIMEOUT * 5),
+            }
+        }
+        assert_eq!(events, [Some(ProtocolErrorKind::HandshakeTimeout), None]);
+        assert_eq!(primary.server().clients().len(), 1);
+    }
+
+    /// 異常終了するプライマリを演じる子プロセスに、アプリケーションIDを渡す環境変数。
+    const CRASH_CHILD_ENV: &str = "INSTANCE_PIPE_TEST_CRASH_APP_ID";
+
+    #[test]
+    fn simultaneous_acquire_elects_one_primary() {
+        const STARTERS: usize = 8;
+        let app_id = format!("instance-pipe-test-race-{}", std::process::id());
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(STARTERS));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for _ in 0..STARTERS {
+            let (app_id, barrier, sender) = (app_id.clone(), barrier.clone(), sender.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                sender.send(SingleInstance::acquire(&app_id)).unwrap();
+            });
+        }
+
+        // セカンダリの接続を受け入れられるよう、プライマリが決まったらイベントを処理し続ける
+        let started = Instant::now();
+        let mut primary = None;
+        let mut secondaries = Vec::new();
+        while secondaries.len() + usize::from(primary.is_some()) < STARTERS {
+            assert!(started.elapsed() < Duration::from_secs(10), "starters did not finish");
+            match receiver.try_recv() {
+                Ok(instance) => match instance.unwrap() {
+                    SingleInstance::Primary(instance) => {
+                        assert!(primary.is_none(), "more than one primary was elected");
+                        primary = Some(instance);
+                    }
+                    SingleInstance::Secondary(instance) => secondaries.push(instance),
+                },
+                Err(_) => match &mut primary {
+                    Some(primary) => {
+                        primary.poll_event().unwrap();
+                    }
+                    None => thread::sleep(Duration::from_millis(1)),
+                },
+            }
+        }
+        assert!(primary.is_some());
+        assert_eq!(secondaries.len(), STARTERS - 1);
+    }
+
+    #[test]
+    fn crashed_primary_is_reclaimed() {
+        if let Ok(app_id) = std::env::var(CRASH_CHILD_ENV) {
+            // 子プロセス: プライマリになった後、後片付けをせずに異常終了する
+            assert!(SingleInstance::acquire(&app_id).unwrap().is_primary());
+            std::process::abort();
+        }
+
+        let app_id = format!("instance-pipe-test-crash-{}", std::process::id());
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "instance::single::tests::crashed_primary_is_reclaimed"])
+            .env(CRASH_CHILD_ENV, &app_id)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        // パニックではなくabortで終了したことを確認する
+        assert_eq!(status.code(), None, "child did not become primary: {}", status);
+
+        assert!(SingleInstance::acquire(&app_id).unwrap().is_primary());
+    }
+}
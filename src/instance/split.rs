@@ -0,0 +1,143 @@
+use crate::instance::event::{DisconnectReason, Event};
+use crate::protocol::ProtocolConfig;
+use crate::Client;
+use serde::{Deserialize, Serialize};
+use std::io::Result;
+use std::time::Duration;
+
+/// [`Client::split`]で分割した受信側。
+///
+/// 書き込み側とは独立して別のスレッドで使用できます。受信中でも書き込み側の送信は妨げられません。
+pub struct ClientReader {
+    client: Client,
+}
+
+/// [`Client::split`]で分割した送信側。
+///
+/// クローンした書き込み側から複数のスレッドで同時に送信しても、フレームが混ざることはありません。
+/// 受信側がポーリング中でも、送信が途中で[`WouldBlock`](std::io::ErrorKind::WouldBlock)により
+/// 失敗することはなく、書き込めるようになるまで待ってから続きを送信します。
+#[derive(Clone)]
+pub struct ClientWriter {
+    client: Client,
+}
+
+impl Client {
+    /// クライアントを受信側と送信側に分割します。
+    ///
+    /// 両者は同じ接続を共有し、それぞれ別のスレッドに移動できます。
+    pub fn split(self) -> (ClientReader, ClientWriter) {
+        (
+            ClientReader {
+                client: self.clone(),
+            },
+            ClientWriter { client: self },
+        )
+    }
+}
+
+impl ClientReader {
+    /// 相手からのイベントをポーリングします。
+    ///
+    /// 動作は[`Client::poll_event`]と同じです。
+    ///
+    /// # エラー
+    /// メッセージのデシリアライズに失敗した場合や、その他のI/Oエラーが発生した場合にエラーを返します。
+    pub fn poll_event<T: for<'a> Deserialize<'a> + 'static>(&mut self) -> Result<Option<Event<T>>> {
+        self.client.poll_event()
+    }
+
+    /// 相手からメッセージを受信します。
+    ///
+    /// # エラー
+    /// メッセージの受信またはデシリアライズに失敗した場合や、
+    /// フレームが最大サイズを超える場合にエラーを返します。
+    pub fn recv<T: for<'a> Deserialize<'a> + Clone + 'static>(&self) -> Result<T> {
+        self.client.recv()
+    }
+
+    /// 現在のタイムアウト時間を取得します。
+    pub fn get_timeout(&self) -> Duration {
+        self.client.get_timeout()
+    }
+
+    /// ポーリング時のタイムアウト時間を設定します。送信側には影響しません。
+    ///
+    /// # 引数
+    /// - `timeout`: ポーリング時の新しいタイムアウト時間。
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.client.set_timeout(timeout);
+    }
+
+    /// この接続に適用されているプロトコルの設定を取得します。
+    pub fn get_config(&self) -> &ProtocolConfig {
+        self.client.get_config()
+    }
+
+    /// 接続が切断されたことを検出済みであれば、その理由を返します。
+    pub fn get_disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.client.get_disconnect_reason()
+    }
+}
+
+impl ClientWriter {
+    /// 相手にメッセージを送信します。
+    ///
+    /// # 引数
+    /// - `message`: 送信するメッセージ。`Serialize`トレイトを実装している必要があります。
+    ///
+    /// # エラー
+    /// メッセージのシリアライズまたは送信に失敗した場合にエラーを返します。
+    pub fn send<T: Serialize>(&self, message: &T) -> Result<()> {
+        self.client.send(message)
+    }
+
+    /// この接続に適用されているプロトコルの設定を取得します。
+    pub fn get_config(&self) -> &ProtocolConfig {
+        self.client.get_config()
+    }
+
+    /// 接続が切断されたことを検出済みであれば、その理由を返します。
+    pub fn get_disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.client.get_disconnect_reason()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Client, Server};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn writer_progresses_while_reader_is_blocked_in_recv() {
+        let name = format!("instance-pipe-test-split-{}", std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let connecting = thread::spawn(move || Client::start(&name));
+        let peer = server.accept().unwrap();
+        let (reader, writer) = connecting.join().unwrap().unwrap().split();
+
+        let (received, receiving) = mpsc::channel();
+        thread::spawn(move || received.send(reader.recv::<u32>()).unwrap());
+        // 受信側がrecvで待ち始めてから送信する
+        thread::sleep(Duration::from_millis(20));
+
+        let (sent, sending) = mpsc::channel();
+        thread::spawn(move || {
+            let result = (0..100u32).try_for_each(|i| writer.send(&i));
+            sent.send((result, writer)).unwrap();
+        });
+        let (result, _writer) = sending
+            .recv_timeout(Duration::from_secs(5))
+            .expect("writer was blocked by the reader");
+        result.unwrap();
+        for i in 0..100u32 {
+            assert_eq!(peer.recv::<u32>().unwrap(), i);
+        }
+
+        peer.send(&1000u32).unwrap();
+        let message = receiving.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(message, 1000);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::instance::event::Event;
+use crate::protocol::{Capabilities, Fingerprint, Frame, FrameKind, ProtocolConfig, Schema};
+use crate::Client;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, Result};
+use std::marker::PhantomData;
+use std::time::Instant;
+
+/// 単一のメッセージ型のみを送受信するクライアント。
+///
+/// 接続時に両端がメッセージ型の[`Fingerprint`]を専用のフレームで交換し、一致しない場合は通信を拒否します。
+/// これにより、片方が`MessageV1`を送信し、もう片方が`MessageV2`としてデコードするといった
+/// 不整合を接続時に検出できます。
+pub struct TypedClient<T> {
+    client: Client,
+    _marker: PhantomData<fn(T) -> T>,
+}
+
+impl<T> Clone for TypedClient<T> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Schema + Serialize + DeserializeOwned + Clone + 'static> TypedClient<T> {
+    /// 指定された名前のサーバーに接続し、フィンガープリントを交換します。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    ///
+    /// # エラー
+    /// 接続に失敗した場合や、サーバーのメッセージ型と一致しない場合にエラーを返します。
+    pub fn start(name: &str) -> Result<Self> {
+        Self::start_with(name, ProtocolConfig::default())
+    }
+
+    /// 指定されたプロトコル設定でサーバーに接続し、フィンガープリントを交換します。
+    ///
+    /// # 引数
+    /// - `name`: 接続するサーバーのパイプまたはソケット名。
+    /// - `config`: この接続に適用するプロトコルの設定。
+    ///
+    /// # エラー
+    /// 接続に失敗した場合や、サーバーのメッセージ型と一致しない場合にエラーを返します。
+    pub fn start_with(name: &str, config: ProtocolConfig) -> Result<Self> {
+        Self::new(Client::start_with(name, config)?)
+    }
+
+    /// 接続済みのクライアントでフィンガープリントを交換します。
+    ///
+    /// サーバー側では[`Server::accept`](crate::Server::accept)で受け入れたクライアントに対して使用します。
+    ///
+    /// # エラー
+    /// 相手がフィンガープリントの交換に対応していない場合や、
+    /// ハンドシェイクのタイムアウト時間内にフィンガープリントを送信しない場合、
+    /// メッセージ型が一致しない場合にエラーを返します。
+    pub fn new(client: Client) -> Result<Self> {
+        if !client.get_capabilities().contains(Capabilities::FINGERPRINT) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Fingerprint exchange was not negotiated for this connection",
+            ));
+        }
+        let local = Fingerprint::of::<T>();
+        client.write_frame(&Frame {
+            kind: FrameKind::Fingerprint,
+            payload: local.encode(),
+        })?;
+
+        let deadline = Instant::now() + client.get_config().get_handshake_timeout();
+        let frame = client.next_frame(FrameKind::Fingerprint, Some(deadline))?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Peer did not send its message fingerprint in time",
+            )
+        })?;
+        local.verify(&Fingerprint::decode(&frame.payload)?)?;
+
+        Ok(Self {
+            client,
+            _marker: PhantomData,
+        })
+    }
+
+    /// メッセージを送信します。
+    ///
+    /// # エラー
+    /// メッセージのシリアライズまたは送信に失敗した場合にエラーを返します。
+    pub fn send(&self, message: &T) -> Result<()> {
+        self.client.send(message)
+    }
+
+    /// メッセージを受信します。
+    ///
+    /// # エラー
+    /// メッセージの受信またはデシリアライズに失敗した場合にエラーを返します。
+    pub fn recv(&self) -> Result<T> {
+        self.client.recv()
+    }
+
+    /// 相手からのイベントをポーリングします。
+    ///
+    /// # エラー
+    /// メッセージの受信またはデシリアライズに失敗した場合にエラーを返します。
+    pub fn poll_event(&mut self) -> Result<Option<Event<T>>> {
+        self.client.poll_event()
+    }
+
+    /// 内部のクライアントへの参照を返します。
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// 内部のクライアントへの可変参照を返します。
+    pub fn client_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// `TypedClient`を消費して内部のクライアントを返します。
+    pub fn into_client(self) -> Client {
+        self.client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ProtocolError, ProtocolErrorKind};
+    use crate::Server;
+    use serde::Deserialize;
+    use std::thread;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    struct MessageV1 {
+        id: u32,
+    }
+
+    impl Schema for MessageV1 {
+        fn schema() -> String {
+            "id:u32".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    struct MessageV2 {
+        id: u64,
+    }
+
+    impl Schema for MessageV2 {
+        fn schema() -> String {
+            "id:u64".to_string()
+        }
+    }
+
+    /// サーバー側を`S`、クライアント側を`C`のメッセージ型として接続し、両端の結果を返します。
+    fn connect<S, C>(test: &str) -> (Result<TypedClient<S>>, Result<TypedClient<C>>)
+    where
+        S: Schema + Serialize + DeserializeOwned + Clone + 'static,
+        C: Schema + Serialize + DeserializeOwned + Clone + Send + 'static,
+    {
+        let name = format!("instance-pipe-test-typed-{}-{}", test, std::process::id());
+        let mut server = Server::start(&name).unwrap();
+        let connecting = thread::spawn(move || TypedClient::<C>::start(&name));
+        let accepted = TypedClient::<S>::new(server.accept().unwrap());
+        (accepted, connecting.join().unwrap())
+    }
+
+    #[test]
+    fn matching_schemas_connect() {
+        let (server, client) = connect::<MessageV1, MessageV1>("match");
+        let (server, client) = (server.unwrap(), client.unwrap());
+        client.send(&MessageV1 { id: 7 }).unwrap();
+        assert_eq!(server.recv().unwrap(), MessageV1 { id: 7 });
+    }
+
+    #[test]
+    fn mismatched_schemas_fail_at_connect() {
+        let (server, client) = connect::<MessageV1, MessageV2>("mismatch");
+        for error in [server.err().unwrap(), client.err().unwrap()] {
+            assert_eq!(ProtocolError::kind_of(&error), Some(ProtocolErrorKind::FingerprintMismatch));
+        }
+    }
+}
@@ -12,6 +12,39 @@ pub mod protocol;
 /// クライアント構造体。サーバーとの通信を管理します。
 pub use instance::client::Client;
 /// イベント関連の機能を提供します。
-pub use instance::event::{Event, EventHandler};
-/// サーバー構造体。クライアントからの接続を待ち受けます。
-pub use instance::server::Server;
+pub use instance::event::{DisconnectReason, Event, EventHandler};
+/// インスタンス名を導出するビルダー。
+pub use instance::name::InstanceName;
+/// メッセージのコーデック。
+pub use protocol::codec::{Codec, CodecKind};
+/// プロトコルの設定とエラー型。
+pub use protocol::{
+    Capabilities, Fingerprint, Framing, OversizePolicy, ProtocolConfig, ProtocolError, ProtocolErrorKind,
+    Schema,
+};
+/// サーバー構造体と接続ID。クライアントからの接続を待ち受けます。
+pub use instance::server::{ConnectionId, Server, StaleSocketPolicy};
+/// サーバーのイベントループで使用するハンドラーと終了ハンドル。
+pub use instance::handler::{ConnectionHandler, ShutdownHandle};
+/// メッセージ型を検証するクライアント。
+pub use instance::typed::TypedClient;
+/// 自動的に再接続するクライアントとその設定。
+pub use instance::reconnect::{ReconnectEvent, ReconnectPolicy, ReconnectingClient};
+/// 分割したクライアントの受信側と送信側。
+pub use instance::split::{ClientReader, ClientWriter};
+/// 相手から受信したRPCリクエスト。
+pub use instance::rpc::RpcRequest;
+/// トピックに配信されたメッセージ。
+pub use instance::pubsub::Publication;
+/// tokioで使用する非同期クライアントとサーバー。
+#[cfg(feature = "async")]
+pub use instance::{
+    async_client::AsyncClient,
+    async_server::AsyncServer,
+    async_stream::{MessageSink, MessageStream},
+};
+/// 単一インスタンス制御の型。
+pub use instance::single::{Activation, ActivationResult, Primary, PrimaryEvent, Secondary, SingleInstance};
+/// [`service!`]マクロが生成するコードから参照する`serde`。
+#[doc(hidden)]
+pub use serde as __serde;
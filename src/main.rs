@@ -1,9 +1,11 @@
-use instance_pipe::{Client, Server, Event};
+use instance_pipe::{
+    ActivationResult, Client, ConnectionHandler, ConnectionId, DisconnectReason, Event, InstanceName, Server,
+    PrimaryEvent, SingleInstance,
+};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::io::{self, Read};
 use std::env;
-use std::thread;
 use std::time::Duration;
 
 // サーバーとクライアント間で送受信するメッセージ構造体。
@@ -15,16 +17,17 @@ enum Message {
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} [server|client]", args[0]);
+    if args.len() < 2 {
+        eprintln!("Usage: {} [server|client|single [ARGS...]]", args[0]);
         std::process::exit(1);
     }
 
     match args[1].as_str() {
         "server" => run_server(),
         "client" => run_client(),
+        "single" => run_single(),
         _ => {
-            eprintln!("Invalid argument. Use 'server' or 'client'.");
+            eprintln!("Invalid argument. Use 'server', 'client' or 'single'.");
             std::process::exit(1);
         }
     }
@@ -35,76 +38,54 @@ fn run_server() -> Result<(), Box<dyn Error>> {
     let mut server = Server::start("key_pipe")?;
     println!("Server started, waiting for connections...");
 
-    loop {
-        match server.poll_event() {
-            Ok(Some(Event::ConnectionAccepted(client))) => {
-                println!("Client connected");
-                // クライアントハンドリングを別スレッドで実行
-                let client_clone = client.clone();
-                thread::spawn(move || handle_client(client_clone).unwrap_or_else(|e| eprintln!("Client handler error: {}", e)));
-            }
-            Ok(Some(Event::MessageSent)) => {
-                println!("Server sent a message (unexpected)");
-            }
-            Ok(Some(Event::MessageReceived(_))) => {
-                println!("Server received a message (unexpected)");
-            }
-            Ok(None) => {
-                // イベントなし、ループ継続
-            }
-            Err(e) => {
-                eprintln!("Server error: {}", e);
-                break;
-            }
-        }
-        thread::sleep(Duration::from_millis(50));
-    }
+    server.run(&mut KeyHandler)?;
 
     server.stop()?;
     println!("Server stopped");
     Ok(())
 }
 
-// クライアントをハンドリングします。
-fn handle_client(mut client: Client) -> Result<(), Box<dyn Error + Send + Sync>> {
-    loop {
-        match client.poll_event::<Message>() {
-            Ok(Some(Event::MessageReceived(Message::Key(key)))) => {
+// 受信したキーに応答するハンドラー。
+struct KeyHandler;
+
+impl ConnectionHandler for KeyHandler {
+    type Message = Message;
+
+    fn on_connect(&mut self, client: &Client) {
+        println!("Client {:?} connected", client.get_connection_id());
+    }
+
+    fn on_message(&mut self, client: &Client, message: Message) {
+        match message {
+            Message::Key(key) => {
                 println!("Server received key: {}", key);
                 // レスポンスを送信
                 let response = Message::Response(format!("Received key: {}", key));
-                client.send(&response)?;
-                println!("Server sent response");
+                match client.send(&response) {
+                    Ok(()) => println!("Server sent response"),
+                    Err(e) => eprintln!("Failed to send response: {}", e),
+                }
             }
-            Ok(Some(Event::MessageReceived(Message::Response(_)))) => {
+            Message::Response(_) => {
                 println!("Server received unexpected response");
             }
-            Ok(Some(Event::MessageSent)) => {
-                println!("Server sent a message (handled)");
-            }
-            Ok(Some(Event::ConnectionAccepted(_))) => {
-                println!("Unexpected connection event in client handler");
-            }
-            Ok(None) => {
-                // イベントなし
-            }
-            Err(e) => {
-                eprintln!("Client handler error: {}", e);
-                break;
-            }
         }
-        thread::sleep(Duration::from_millis(50));
     }
-    client.stop()?;
-    Ok(())
+
+    fn on_disconnect(&mut self, connection: ConnectionId, reason: DisconnectReason) {
+        println!("Client {:?} disconnected: {}", connection, reason);
+    }
+
+    fn on_error(&mut self, client: &Client, error: io::Error) {
+        // 互換性のないクライアントとの接続のみが影響を受け、待ち受けは継続
+        eprintln!("Client {:?} error: {}", client.get_connection_id(), error);
+    }
 }
 
 // クライアントモードを実行します。
 fn run_client() -> Result<(), Box<dyn Error>> {
-    // サーバーが起動するのを待つ
-    thread::sleep(Duration::from_millis(100));
-
-    let mut client = Client::start("key_pipe")?;
+    // サーバーが起動するのを待って接続
+    let mut client = Client::connect_timeout("key_pipe", Duration::from_secs(5))?;
     println!("Client connected to server");
 
     // 標準入力からキーを読み込む
@@ -134,6 +115,21 @@ fn run_client() -> Result<(), Box<dyn Error>> {
             Ok(Some(Event::ConnectionAccepted(_))) => {
                 println!("Unexpected connection event in client");
             }
+            Ok(Some(Event::Disconnected { reason, .. })) => {
+                eprintln!("Server disconnected: {}", reason);
+                break;
+            }
+            Ok(Some(Event::ProtocolError(e))) => {
+                eprintln!("Client protocol error: {}", e);
+                break;
+            }
+            Ok(Some(Event::Goodbye { .. })) => {
+                eprintln!("Server is shutting down");
+                break;
+            }
+            Ok(Some(_)) => {
+                println!("Unexpected event in client");
+            }
             Ok(None) => {
                 // イベントなし
             }
@@ -142,10 +138,44 @@ fn run_client() -> Result<(), Box<dyn Error>> {
                 break;
             }
         }
-        thread::sleep(Duration::from_millis(50));
     }
 
     client.stop()?;
     println!("Client stopped");
     Ok(())
-}
\ No newline at end of file
+}
+
+// 単一インスタンスモードを実行します。
+fn run_single() -> Result<(), Box<dyn Error>> {
+    match SingleInstance::acquire_with(&InstanceName::new("key_pipe_single"), &["LANG"])? {
+        SingleInstance::Primary(mut primary) => {
+            println!("Primary instance started, waiting for other instances...");
+            loop {
+                match primary.poll_event() {
+                    Ok(Some(PrimaryEvent::Activated(activation))) => {
+                        println!(
+                            "Activated from {}: {:?} {:?}",
+                            activation.cwd.display(),
+                            activation.args,
+                            activation.env
+                        );
+                        if let Err(e) = activation.respond(&ActivationResult::Accepted) {
+                            eprintln!("Failed to reply to activation: {}", e);
+                        }
+                    }
+                    Ok(_) => {
+                        // イベントなし、ループ継続
+                    }
+                    // 待ち受けの失敗は回復しないため、再試行せずに終了する
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        SingleInstance::Secondary(mut secondary) => {
+            println!("Forwarded arguments to the primary instance");
+            let result = secondary.wait_for_result(Duration::from_secs(5))?;
+            println!("Primary replied: {:?}", result);
+            std::process::exit(result.exit_code());
+        }
+    }
+}
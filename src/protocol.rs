@@ -1,6 +1,561 @@
 
-use serde::{de::DeserializeOwned, Serialize};
+/// メッセージのエンコード方式を提供するモジュール。
+pub mod codec;
+
+use codec::{Codec, CodecKind};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
 use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// 既定の最大フレームサイズ（16 MiB）。
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// 既定のハンドシェイクのタイムアウト時間。
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ハンドシェイクの先頭に置かれるマジックバイト。
+pub const MAGIC: [u8; 4] = *b"IPIP";
+/// このビルドが使用するプロトコルバージョン。
+///
+/// - `1`: 長さプレフィックスに続けてメッセージ本体を送信します。
+/// - `2`: 長さプレフィックスの直後に[`FrameKind`]を示す1バイトを追加しました。
+pub const PROTOCOL_VERSION: u16 = 2;
+/// このビルドが接続を受け入れる最も古いプロトコルバージョン。
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+/// ハンドシェイクメッセージのバイト数（マジック4バイト、バージョン2バイト、機能4バイト）。
+pub const HELLO_LEN: usize = 10;
+
+/// 最大サイズを超えるフレームを受信した場合の処理方法。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// フレーム本体を読み捨て、以降のフレームの受信を継続します。
+    Skip,
+    /// 接続を閉じ、以降の受信をすべてエラーにします。
+    Close,
+}
+
+/// ストリーム上でのメッセージの区切り方。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// リトルエンディアン4バイトの長さプレフィックスに続けて本体を送信します。
+    #[default]
+    LengthPrefixed,
+    /// 1行に1つのJSONドキュメントを送信します（NDJSON）。
+    ///
+    /// スクリプト言語や`socat`などから直接通信できるよう、ハンドシェイクは行わず、
+    /// コーデックの設定にかかわらずJSONでエンコードします。
+    #[cfg(feature = "json")]
+    Ndjson,
+}
+
+/// プロトコルの動作を設定する構造体。
+///
+/// [`Client::start_with`](crate::Client::start_with)や
+/// [`Server::start_with`](crate::Server::start_with)に渡すことで、
+/// クライアントおよびサーバーが受け入れた接続に適用されます。
+#[derive(Clone, Debug)]
+pub struct ProtocolConfig {
+    max_frame_size: usize,
+    oversize_policy: OversizePolicy,
+    handshake_timeout: Duration,
+    codec: CodecKind,
+    framing: Framing,
+    require_same_user: bool,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtocolConfig {
+    /// 既定値で設定を作成します。
+    ///
+    /// 最大フレームサイズは[`DEFAULT_MAX_FRAME_SIZE`]、超過時の処理は[`OversizePolicy::Close`]、
+    /// コーデックは[`CodecKind::Bincode`]、フレーミングは[`Framing::LengthPrefixed`]です。
+    /// 相手のユーザーは検証しません。
+    pub fn new() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            oversize_policy: OversizePolicy::Close,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            codec: CodecKind::Bincode,
+            framing: Framing::LengthPrefixed,
+            require_same_user: false,
+        }
+    }
+
+    /// 送受信できるフレーム本体の最大バイト数を設定します。
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// 最大サイズを超えるフレームを受信した場合の処理方法を設定します。
+    pub fn oversize_policy(mut self, policy: OversizePolicy) -> Self {
+        self.oversize_policy = policy;
+        self
+    }
+
+    /// 接続時のハンドシェイクで相手の応答を待つ最大時間を設定します。
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// メッセージのエンコードに使用するコーデックを、組み込みのコーデックから選択します。
+    pub fn codec(mut self, codec: CodecKind) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// メッセージの区切り方を設定します。
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// 接続の相手が自身と同じユーザーのプロセスであることを要求するかどうかを設定します。
+    ///
+    /// 有効にすると、ハンドシェイクの前にソケットの相手の資格情報（Linuxでは`SO_PEERCRED`、
+    /// macOSやBSDでは`getpeereid`）を取得し、実効ユーザーIDが一致しない接続を
+    /// [`ProtocolErrorKind::UnauthorizedPeer`]のエラーで拒否します。
+    /// クライアントでは接続先のサーバーを、サーバーでは受け入れたクライアントを検証します。
+    /// 資格情報を取得できないプラットフォームでは、すべての接続を拒否します。
+    pub fn require_same_user(mut self, enabled: bool) -> Self {
+        self.require_same_user = enabled;
+        self
+    }
+
+    /// 現在の最大フレームサイズを取得します。
+    pub fn get_max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// 現在の超過時の処理方法を取得します。
+    pub fn get_oversize_policy(&self) -> OversizePolicy {
+        self.oversize_policy
+    }
+
+    /// 現在のハンドシェイクのタイムアウト時間を取得します。
+    pub fn get_handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// 実際に使用されるコーデックを取得します。
+    ///
+    /// NDJSONモードでは、設定にかかわらず`CodecKind::Json`を返します。
+    pub fn get_codec(&self) -> CodecKind {
+        match self.framing {
+            Framing::LengthPrefixed => self.codec,
+            #[cfg(feature = "json")]
+            Framing::Ndjson => CodecKind::Json,
+        }
+    }
+
+    /// 現在のメッセージの区切り方を取得します。
+    pub fn get_framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// 相手が同じユーザーであることを要求するかどうかを取得します。
+    pub fn get_require_same_user(&self) -> bool {
+        self.require_same_user
+    }
+
+    /// 接続時にハンドシェイクを行うかどうかを返します。
+    pub fn uses_handshake(&self) -> bool {
+        self.framing == Framing::LengthPrefixed
+    }
+}
+
+/// 接続の両端が対応している機能を表すビット集合。
+///
+/// ハンドシェイクで互いの機能を交換し、両者が対応している機能のみが有効になります。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// 機能を持たない空の集合。
+    pub const NONE: Self = Self(0);
+    /// 相関IDによるリクエスト/レスポンス（RPC）に対応しています。
+    pub const RPC: Self = Self(1 << 0);
+    /// トピックによる出版/購読に対応しています。
+    pub const PUBSUB: Self = Self(1 << 1);
+    /// 終了時のgoodbyeフレームによる通知に対応しています。
+    pub const GOODBYE: Self = Self(1 << 2);
+    /// 専用のフレームによるメッセージ型のフィンガープリントの交換に対応しています。
+    pub const FINGERPRINT: Self = Self(1 << 3);
+
+    /// このビルドが対応しているすべての機能。
+    pub const fn supported() -> Self {
+        Self(Self::RPC.0 | Self::PUBSUB.0 | Self::GOODBYE.0 | Self::FINGERPRINT.0)
+    }
+
+    /// ビット表現から機能の集合を作成します。
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// 機能の集合のビット表現を返します。
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// 指定された機能をすべて含んでいるかどうかを返します。
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// 両方の集合に含まれる機能のみを返します。
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// 接続の確立時に交換されるハンドシェイクメッセージ。
+///
+/// ユーザーメッセージより前に、マジックバイト・プロトコルバージョン・機能のビット集合を
+/// 固定長のバイナリ形式（リトルエンディアン）で送信します。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    /// 送信側のプロトコルバージョン。
+    pub version: u16,
+    /// 送信側が対応している機能。
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// このビルドのハンドシェイクメッセージを作成します。
+    pub fn local() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+        }
+    }
+
+    /// ハンドシェイクメッセージをバイト列にエンコードします。
+    pub fn encode(&self) -> [u8; HELLO_LEN] {
+        let mut bytes = [0u8; HELLO_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6..].copy_from_slice(&self.capabilities.bits().to_le_bytes());
+        bytes
+    }
+
+    /// バイト列からハンドシェイクメッセージをデコードします。
+    ///
+    /// # エラー
+    /// マジックバイトが一致しない場合にエラーを返します。
+    pub fn decode(bytes: &[u8; HELLO_LEN]) -> io::Result<Self> {
+        if bytes[..4] != MAGIC {
+            return Err(ProtocolError::new(
+                ProtocolErrorKind::BadMagic,
+                "Peer did not send the instance-pipe handshake",
+            )
+            .into());
+        }
+        Ok(Self {
+            version: u16::from_le_bytes([bytes[4], bytes[5]]),
+            capabilities: Capabilities::from_bits(u32::from_le_bytes([
+                bytes[6], bytes[7], bytes[8], bytes[9],
+            ])),
+        })
+    }
+
+    /// 相手のハンドシェイクメッセージと照合し、接続で使用するバージョンと機能を決定します。
+    ///
+    /// バージョンは両者の低い方、機能は両者が対応しているもののみになります。
+    /// 相手の方が新しい場合は、相手側がこちらのバージョンに対応できるかを判断します。
+    ///
+    /// # エラー
+    /// 相手のバージョンがこのビルドで対応できない場合にエラーを返します。
+    pub fn negotiate(&self, peer: &Self) -> io::Result<Self> {
+        if peer.version < MIN_PROTOCOL_VERSION {
+            return Err(ProtocolError::new(
+                ProtocolErrorKind::VersionMismatch,
+                format!(
+                    "Protocol version mismatch: local {} (accepts {}..={}), peer {}",
+                    self.version, MIN_PROTOCOL_VERSION, self.version, peer.version
+                ),
+            )
+            .into());
+        }
+        Ok(Self {
+            version: self.version.min(peer.version),
+            capabilities: self.capabilities.intersection(peer.capabilities),
+        })
+    }
+}
+
+/// メッセージ型のスキーマを表すトレイト。
+///
+/// [`Fingerprint`]の計算に使用されます。
+/// フィールドの名前と型の並びなど、ワイヤー上の表現を決める内容を`schema`で返してください。
+/// フィールド構成を変更した際にスキーマも変わるようにすることで、
+/// 型名が同じでも互換性のないビルド同士の通信を検出できます。
+///
+/// # 例
+/// ```
+/// use instance_pipe::Schema;
+///
+/// struct Message {
+///     id: u32,
+///     text: String,
+/// }
+///
+/// impl Schema for Message {
+///     fn schema() -> String {
+///         "id: u32, text: String".to_string()
+///     }
+/// }
+/// ```
+pub trait Schema {
+    /// 型のスキーマを表す文字列を返します。
+    fn schema() -> String;
+}
+
+/// メッセージ型を識別するフィンガープリント。
+///
+/// 型名と、型名およびスキーマのSHA-256ハッシュで構成されます。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    /// メッセージ型の名前。
+    pub type_name: String,
+    /// 型名とスキーマから計算したハッシュ。
+    pub schema_hash: [u8; 32],
+}
+
+impl Fingerprint {
+    /// 指定された型のフィンガープリントを計算します。
+    pub fn of<T: Schema + ?Sized>() -> Self {
+        let type_name = std::any::type_name::<T>().to_string();
+        let mut hasher = Sha256::new();
+        hasher.update((type_name.len() as u64).to_le_bytes());
+        hasher.update(type_name.as_bytes());
+        hasher.update(T::schema().as_bytes());
+        Self {
+            type_name,
+            schema_hash: hasher.finalize().into(),
+        }
+    }
+
+    /// フィンガープリントフレームの本体にエンコードします。
+    ///
+    /// 本体はハッシュ32バイトと、それに続くUTF-8の型名で構成されます。
+    /// コーデックの設定にかかわらず同じ表現になります。
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.schema_hash.to_vec();
+        bytes.extend_from_slice(self.type_name.as_bytes());
+        bytes
+    }
+
+    /// フィンガープリントフレームの本体からデコードします。
+    ///
+    /// # エラー
+    /// 本体がハッシュより短い場合や、型名がUTF-8でない場合にエラーを返します。
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = || ProtocolError::new(ProtocolErrorKind::InvalidFrame, "Malformed fingerprint frame");
+        let (schema_hash, type_name) = bytes.split_first_chunk::<32>().ok_or_else(invalid)?;
+        Ok(Self {
+            type_name: String::from_utf8(type_name.to_vec()).map_err(|_| invalid())?,
+            schema_hash: *schema_hash,
+        })
+    }
+
+    /// 相手のフィンガープリントと一致するかを確認します。
+    ///
+    /// # エラー
+    /// フィンガープリントが一致しない場合にエラーを返します。
+    pub fn verify(&self, peer: &Self) -> io::Result<()> {
+        if self != peer {
+            return Err(ProtocolError::new(
+                ProtocolErrorKind::FingerprintMismatch,
+                format!(
+                    "Message type mismatch: local {} ({}), peer {} ({})",
+                    self.type_name,
+                    hex(&self.schema_hash[..8]),
+                    peer.type_name,
+                    hex(&peer.schema_hash[..8])
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// バイト列を16進数の文字列に変換します。
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// プロトコルエラーの種類。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// フレームが最大サイズを超えています。
+    FrameTooLarge,
+    /// 相手がハンドシェイクのマジックバイトを送信しませんでした。
+    BadMagic,
+    /// 相手のプロトコルバージョンに互換性がありません。
+    VersionMismatch,
+    /// 相手が異なる型またはスキーマのメッセージを使用しています。
+    FingerprintMismatch,
+    /// フレームの種類が不明、または内容が不正です。
+    InvalidFrame,
+    /// 相手がハンドシェイクのタイムアウト時間内にハンドシェイクメッセージを送信しませんでした。
+    HandshakeTimeout,
+    /// 接続の相手が、要求されたユーザーと異なるユーザーのプロセスです。
+    UnauthorizedPeer,
+    /// 受け取り手を待っているフレームが上限に達したため、受信したフレームを破棄しました。
+    QueueFull,
+}
+
+/// プロトコル違反を表すエラー。
+///
+/// `io::Error`（種類は[`InvalidData`](io::ErrorKind::InvalidData)）に包まれて返されます。
+/// [`ProtocolError::kind_of`]で元の種類を取り出すことができます。
+#[derive(Clone, Debug)]
+pub struct ProtocolError {
+    kind: ProtocolErrorKind,
+    message: String,
+}
+
+impl ProtocolError {
+    /// 新しいプロトコルエラーを作成します。
+    pub fn new(kind: ProtocolErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// エラーの種類を返します。
+    pub fn kind(&self) -> ProtocolErrorKind {
+        self.kind
+    }
+
+    /// `io::Error`がプロトコルエラーであれば、その種類を返します。
+    pub fn kind_of(error: &io::Error) -> Option<ProtocolErrorKind> {
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Self>())
+            .map(Self::kind)
+    }
+
+    /// `io::Error`に包まれたプロトコルエラーを取り出します。
+    ///
+    /// プロトコルエラー以外の場合はNoneを返します。
+    pub fn from_io(error: &io::Error) -> Option<Self> {
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Self>())
+            .cloned()
+    }
+
+    /// 最大サイズを超えるフレームのエラーを作成します。
+    fn frame_too_large(len: usize, max: usize) -> Self {
+        Self::new(
+            ProtocolErrorKind::FrameTooLarge,
+            format!("Frame of {} bytes exceeds the maximum of {} bytes", len, max),
+        )
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(error: ProtocolError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// フレームの種類。
+///
+/// 長さプレフィックス付きのフレーミングでは、フレーム本体の先頭1バイトで種類を示します。
+/// NDJSONモードでは常に[`FrameKind::Data`]として扱われます。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// ユーザーメッセージ。
+    Data = 0,
+    /// 相関ID付きのRPCリクエスト。
+    Request = 1,
+    /// 相関ID付きのRPCレスポンス。
+    Response = 2,
+    /// トピックパターンの購読。
+    Subscribe = 3,
+    /// トピックパターンの購読解除。
+    Unsubscribe = 4,
+    /// トピック付きで配信されたメッセージ。
+    Publish = 5,
+    /// 送信側が接続を終了することの通知。
+    Goodbye = 6,
+    /// メッセージ型のフィンガープリント。
+    Fingerprint = 7,
+}
+
+impl FrameKind {
+    /// この種類のフレームを送受信するために、ハンドシェイクで合意している必要がある機能を返します。
+    pub(crate) fn required_capability(self) -> Capabilities {
+        match self {
+            Self::Data => Capabilities::NONE,
+            Self::Request | Self::Response => Capabilities::RPC,
+            Self::Subscribe | Self::Unsubscribe | Self::Publish => Capabilities::PUBSUB,
+            Self::Goodbye => Capabilities::GOODBYE,
+            Self::Fingerprint => Capabilities::FINGERPRINT,
+        }
+    }
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(Self::Data),
+            1 => Ok(Self::Request),
+            2 => Ok(Self::Response),
+            3 => Ok(Self::Subscribe),
+            4 => Ok(Self::Unsubscribe),
+            5 => Ok(Self::Publish),
+            6 => Ok(Self::Goodbye),
+            7 => Ok(Self::Fingerprint),
+            _ => Err(ProtocolError::new(
+                ProtocolErrorKind::InvalidFrame,
+                format!("Unknown frame kind {}", value),
+            )
+            .into()),
+        }
+    }
+}
+
+/// 種類と本体で構成される1つのフレーム。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// フレームの種類。
+    pub kind: FrameKind,
+    /// フレームの本体。
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// ユーザーメッセージのフレームを作成します。
+    pub fn data(payload: Vec<u8>) -> Self {
+        Self {
+            kind: FrameKind::Data,
+            payload,
+        }
+    }
+}
 
 /// メッセージをシリアライズして指定されたライターに送信します。
 ///
@@ -13,23 +568,86 @@ use std::io::{self, Read, Write};
 /// # エラー
 /// I/Oエラーまたはシリアライズエラーが発生した場合に`io::Result`を返します。
 pub fn send_message<T: Serialize, W: Write>(writer: &mut W, message: &T) -> io::Result<()> {
-    // bincode v2 を使ってメッセージをバイナリにシリアライズ
-    let encoded = bincode::serde::encode_to_vec(message, bincode::config::standard())
-        .map_err(io::Error::other)?;
+    send_message_with(writer, message, &ProtocolConfig::default())
+}
 
-    // メッセージの長さをリトルエンディアンで4バイトのプレフィックスとして書き込む
-    let len = encoded.len() as u32;
-    writer.write_all(&len.to_le_bytes())?;
-    
-    // メッセージデータを書き込む
-    writer.write_all(&encoded)?;
-    writer.flush()?;
-    Ok(())
+/// 指定された設定でメッセージをシリアライズして送信します。
+///
+/// メッセージは設定されたコーデックでエンコードされ、設定されたフレーミングで送信されます。
+///
+/// # 引数
+/// - `writer`: メッセージの書き込み先となる`Write`トレイトを実装するオブジェクト。
+/// - `message`: 送信するメッセージ。
+/// - `config`: プロトコルの設定。
+///
+/// # エラー
+/// I/Oエラーまたはシリアライズエラーが発生した場合や、
+/// エンコード後のメッセージが最大フレームサイズを超える場合に`io::Result`を返します。
+pub fn send_message_with<T: Serialize, W: Write>(
+    writer: &mut W,
+    message: &T,
+    config: &ProtocolConfig,
+) -> io::Result<()> {
+    let encoded = config.get_codec().encode(message)?;
+    write_frame(writer, &Frame::data(encoded), config)
+}
+
+/// フレームを、設定されたフレーミングで書き込みます。
+///
+/// # エラー
+/// I/Oエラーが発生した場合や、フレームが最大フレームサイズを超える場合、
+/// NDJSONモードでユーザーメッセージ以外のフレームを送信しようとした場合に`io::Result`を返します。
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame, config: &ProtocolConfig) -> io::Result<()> {
+    writer.write_all(&encode_frame(frame, config)?)?;
+    writer.flush()
+}
+
+/// フレームを、設定されたフレーミングでバイト列にエンコードします。
+///
+/// 長さプレフィックス付きのフレーミングでは、長さは種類を示す1バイトを含みます。
+/// NDJSONモードでは種類を送信しないため、受信側と同じく改行を除いた行の長さを最大フレームサイズと比較します。
+///
+/// # エラー
+/// フレームが最大フレームサイズを超える場合や、
+/// NDJSONモードでユーザーメッセージ以外のフレームをエンコードしようとした場合に`io::Result`を返します。
+pub fn encode_frame(frame: &Frame, config: &ProtocolConfig) -> io::Result<Vec<u8>> {
+    match config.framing {
+        Framing::LengthPrefixed => {
+            let len = frame.payload.len() + 1;
+            if len > config.max_frame_size || len > u32::MAX as usize {
+                return Err(ProtocolError::frame_too_large(len, config.max_frame_size).into());
+            }
+            let mut bytes = Vec::with_capacity(len + 4);
+            // フレームの長さをリトルエンディアンで4バイトのプレフィックスとして書き込む
+            bytes.extend_from_slice(&(len as u32).to_le_bytes());
+            bytes.push(frame.kind as u8);
+            bytes.extend_from_slice(&frame.payload);
+            Ok(bytes)
+        }
+        #[cfg(feature = "json")]
+        Framing::Ndjson => {
+            if frame.kind != FrameKind::Data {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "NDJSON framing only carries plain messages",
+                ));
+            }
+            let len = frame.payload.len();
+            if len > config.max_frame_size {
+                return Err(ProtocolError::frame_too_large(len, config.max_frame_size).into());
+            }
+            let mut bytes = Vec::with_capacity(len + 1);
+            // コンパクトなJSONは改行を含まないため、改行をそのまま区切りとして使用できる
+            bytes.extend_from_slice(&frame.payload);
+            bytes.push(b'\n');
+            Ok(bytes)
+        }
+    }
 }
 
 /// 指定されたリーダーからメッセージを受信し、デシリアライズします。
 ///
-/// 長さプレフィックスを読み取り、bincode形式でデコードします。
+/// 長さプレフィックスと種類を読み取り、bincode形式でデコードします。
 ///
 /// # 引数
 /// - `reader`: メッセージの読み込み元となる`Read`トレイトを実装するオブジェクト。
@@ -37,16 +655,515 @@ pub fn send_message<T: Serialize, W: Write>(writer: &mut W, message: &T) -> io::
 /// # エラー
 /// I/Oエラーまたはデシリアライズエラーが発生した場合に`io::Result`を返します。
 pub fn recv_message<T: DeserializeOwned, R: Read>(reader: &mut R) -> io::Result<T> {
+    recv_message_with(reader, &ProtocolConfig::default())
+}
+
+/// 指定された設定でメッセージを受信し、デシリアライズします。
+///
+/// メッセージは設定されたコーデックでデコードされます。
+/// 長さプレフィックスが最大フレームサイズを超える場合はメモリを確保せずにエラーを返します。
+/// [`OversizePolicy::Skip`]の場合は、次のフレームを受信できるようフレーム本体を読み捨ててからエラーを返します。
+///
+/// # 引数
+/// - `reader`: メッセージの読み込み元となる`Read`トレイトを実装するオブジェクト。
+/// - `config`: プロトコルの設定。
+///
+/// # エラー
+/// I/Oエラーまたはデシリアライズエラーが発生した場合や、
+/// フレームが最大サイズを超える場合に`io::Result`を返します。
+pub fn recv_message_with<T: DeserializeOwned, R: Read>(
+    reader: &mut R,
+    config: &ProtocolConfig,
+) -> io::Result<T> {
+    #[cfg(feature = "json")]
+    if config.framing == Framing::Ndjson {
+        return recv_line(reader, config);
+    }
+
     let mut len_bytes = [0u8; 4];
     reader.read_exact(&mut len_bytes)?;
-    
+
     let len = u32::from_le_bytes(len_bytes) as usize;
-    let mut encoded = vec![0u8; len];
-    reader.read_exact(&mut encoded)?;
-
-    // bincode v2 を使ってバイナリをメッセージにデシリアライズ
-    let (message, _) = bincode::serde::decode_from_slice(&encoded, bincode::config::standard())
-        .map_err(io::Error::other)?;
-    
-    Ok(message)
-}
\ No newline at end of file
+    if len > config.max_frame_size {
+        if config.oversize_policy == OversizePolicy::Skip {
+            io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+        }
+        return Err(ProtocolError::frame_too_large(len, config.max_frame_size).into());
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+
+    let frame = split_frame(body)?;
+    if frame.kind != FrameKind::Data {
+        return Err(ProtocolError::new(
+            ProtocolErrorKind::InvalidFrame,
+            format!("Expected a message frame, received {:?}", frame.kind),
+        )
+        .into());
+    }
+    config.get_codec().decode(&frame.payload)
+}
+
+/// 長さプレフィックスを除いたフレーム本体を、種類と本体に分割します。
+fn split_frame(mut body: Vec<u8>) -> io::Result<Frame> {
+    if body.is_empty() {
+        return Err(ProtocolError::new(ProtocolErrorKind::InvalidFrame, "Empty frame").into());
+    }
+    let kind = FrameKind::try_from(body.remove(0))?;
+    Ok(Frame { kind, payload: body })
+}
+
+/// 指定されたリーダーから1行を読み込み、JSONとしてデシリアライズします。
+///
+/// 次の行のバイト列を読み込まないよう、1バイトずつ読み込みます。空行は無視されます。
+#[cfg(feature = "json")]
+fn recv_line<T: DeserializeOwned, R: Read>(reader: &mut R, config: &ProtocolConfig) -> io::Result<T> {
+    let mut line = Vec::new();
+    let mut oversize = false;
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        if byte[0] != b'\n' {
+            if line.len() < config.max_frame_size {
+                line.push(byte[0]);
+                continue;
+            }
+            if config.oversize_policy == OversizePolicy::Close {
+                return Err(ProtocolError::frame_too_large(line.len() + 1, config.max_frame_size).into());
+            }
+            oversize = true;
+            continue;
+        }
+        if oversize {
+            return Err(ProtocolError::frame_too_large(line.len() + 1, config.max_frame_size).into());
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            return config.get_codec().decode(&line);
+        }
+        line.clear();
+    }
+}
+
+/// フレームを逐次的に組み立てるデコーダー。
+///
+/// 長さプレフィックス付きのフレームと、NDJSONの行の両方に対応します。
+/// 非ブロッキングモードでは、1回の読み込みでフレームの一部しか届かないことがあります。
+/// `FrameDecoder`は読み込んだバイト列を内部に保持し続けるため、
+/// データがどのように分割されて届いてもフレームの境界を失いません。
+/// 最大サイズを超えるフレームは、設定に応じて読み捨てるか、以降の受信をすべて拒否します。
+#[derive(Default, Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    config: ProtocolConfig,
+    /// 読み捨て中のフレーム本体の残りバイト数。
+    skipping: usize,
+    /// 次の改行まで読み捨て中かどうか（NDJSONモード用）。
+    #[cfg(feature = "json")]
+    skipping_line: bool,
+    /// [`OversizePolicy::Close`]により受信を停止した原因となったフレームの長さ。
+    closed: Option<usize>,
+}
+
+impl FrameDecoder {
+    /// 既定の設定で空のデコーダーを作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 指定された設定で空のデコーダーを作成します。
+    pub fn with_config(config: ProtocolConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// リーダーから1回だけ読み込み、読み込んだバイト列を内部バッファに追加します。
+    ///
+    /// 読み込んだバイト数を返します。`0`は接続が閉じられたことを示します。
+    ///
+    /// # エラー
+    /// 読み込みに失敗した場合にエラーを返します。非ブロッキングモードでデータがない場合は
+    /// [`WouldBlock`](io::ErrorKind::WouldBlock)となり、内部の状態は変化しません。
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 受信済みのバイト列を内部バッファに追加します。
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// 完全に受信済みのフレームがあれば取り出します。
+    ///
+    /// フレームが揃っていない場合はNoneを返し、受信済みのバイト列はそのまま保持されます。
+    ///
+    /// # エラー
+    /// フレームが最大サイズを超える場合や、フレームの種類が不明な場合にエラーを返します。
+    /// [`OversizePolicy::Skip`]の場合は一度だけエラーを返し、以降は続くフレームを取り出せます。
+    /// [`OversizePolicy::Close`]の場合は以降の呼び出しもすべてエラーになります。
+    pub fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if let Some(len) = self.closed {
+            return Err(ProtocolError::frame_too_large(len, self.config.max_frame_size).into());
+        }
+        match self.config.framing {
+            Framing::LengthPrefixed => match self.next_length_prefixed()? {
+                Some(body) => split_frame(body).map(Some),
+                None => Ok(None),
+            },
+            #[cfg(feature = "json")]
+            Framing::Ndjson => Ok(self.next_line()?.map(Frame::data)),
+        }
+    }
+
+    /// 長さプレフィックス付きのフレーム本体を取り出します。
+    fn next_length_prefixed(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.skipping > 0 {
+            let n = self.skipping.min(self.buffer.len());
+            self.buffer.drain(..n);
+            self.skipping -= n;
+            if self.skipping > 0 {
+                return Ok(None);
+            }
+        }
+
+        let Some(len_bytes) = self.buffer.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]) as usize;
+        if len > self.config.max_frame_size {
+            match self.config.oversize_policy {
+                OversizePolicy::Skip => {
+                    self.buffer.drain(..4);
+                    self.skipping = len;
+                }
+                OversizePolicy::Close => {
+                    self.buffer.clear();
+                    self.closed = Some(len);
+                }
+            }
+            return Err(ProtocolError::frame_too_large(len, self.config.max_frame_size).into());
+        }
+        if self.buffer.len() < 4 + len {
+            return Ok(None);
+        }
+        let frame = self.buffer[4..4 + len].to_vec();
+        self.buffer.drain(..4 + len);
+        Ok(Some(frame))
+    }
+
+    /// 改行で区切られた1行を取り出します。空行は無視されます。
+    #[cfg(feature = "json")]
+    fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let newline = self.buffer.iter().position(|&b| b == b'\n');
+            if self.skipping_line {
+                match newline {
+                    Some(i) => {
+                        self.buffer.drain(..=i);
+                        self.skipping_line = false;
+                        continue;
+                    }
+                    None => {
+                        self.buffer.clear();
+                        return Ok(None);
+                    }
+                }
+            }
+
+            let len = newline.unwrap_or(self.buffer.len());
+            if len > self.config.max_frame_size {
+                match (self.config.oversize_policy, newline) {
+                    (OversizePolicy::Skip, Some(i)) => {
+                        self.buffer.drain(..=i);
+                    }
+                    (OversizePolicy::Skip, None) => {
+                        self.buffer.clear();
+                        self.skipping_line = true;
+                    }
+                    (OversizePolicy::Close, _) => {
+                        self.buffer.clear();
+                        self.closed = Some(len);
+                    }
+                }
+                return Err(ProtocolError::frame_too_large(len, self.config.max_frame_size).into());
+            }
+
+            let Some(i) = newline else {
+                return Ok(None);
+            };
+            let mut line: Vec<u8> = self.buffer.drain(..=i).collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                return Ok(Some(line));
+            }
+        }
+    }
+
+    /// [`OversizePolicy::Close`]により受信を停止しているかどうかを返します。
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// デコーダーから取り出せるフレームをすべて取り出し、エラーはその種類として記録します。
+    ///
+    /// 受信を停止した場合は、それ以上取り出しません。
+    fn drain(decoder: &mut FrameDecoder, out: &mut Vec<Result<Frame, ProtocolErrorKind>>) {
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => out.push(Ok(frame)),
+                Ok(None) => return,
+                Err(e) => {
+                    out.push(Err(ProtocolError::kind_of(&e).expect("protocol error")));
+                    if decoder.is_closed() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// バイト列を分割した順にデコーダーに渡し、取り出せた結果を順に返します。
+    ///
+    /// 受信を停止した後のバイト列は渡しません。
+    fn decode_chunks<'a>(
+        config: &ProtocolConfig,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<Result<Frame, ProtocolErrorKind>> {
+        let mut decoder = FrameDecoder::with_config(config.clone());
+        let mut out = Vec::new();
+        for chunk in chunks {
+            if decoder.is_closed() {
+                break;
+            }
+            decoder.extend(chunk);
+            drain(&mut decoder, &mut out);
+        }
+        out
+    }
+
+    /// バイト列を1バイトずつデコーダーに渡します。
+    fn decode_bytewise(config: &ProtocolConfig, bytes: &[u8]) -> Vec<Result<Frame, ProtocolErrorKind>> {
+        decode_chunks(config, bytes.chunks(1))
+    }
+
+    /// バイト列を`at`の位置で2つに分けてデコーダーに渡します。
+    fn decode_split(config: &ProtocolConfig, bytes: &[u8], at: usize) -> Vec<Result<Frame, ProtocolErrorKind>> {
+        decode_chunks(config, [&bytes[..at], &bytes[at..]])
+    }
+
+    fn frames() -> Vec<Frame> {
+        vec![
+            Frame::data(b"hello".to_vec()),
+            Frame {
+                kind: FrameKind::Request,
+                payload: vec![0; 300],
+            },
+            Frame::data(Vec::new()),
+            Frame {
+                kind: FrameKind::Goodbye,
+                payload: Vec::new(),
+            },
+        ]
+    }
+
+    fn encode_all(frames: &[Frame], config: &ProtocolConfig) -> Vec<u8> {
+        frames
+            .iter()
+            .flat_map(|frame| encode_frame(frame, config).unwrap())
+            .collect()
+    }
+
+    /// 長さプレフィックスを`len`とする、本体が`len`バイトのフレームのバイト列を作成します。
+    fn raw_frame(len: usize) -> Vec<u8> {
+        let mut bytes = (len as u32).to_le_bytes().to_vec();
+        bytes.push(FrameKind::Data as u8);
+        bytes.resize(4 + len, 0xAB);
+        bytes
+    }
+
+    #[test]
+    fn length_prefixed_bytewise() {
+        let config = ProtocolConfig::new();
+        let frames = frames();
+        let decoded = decode_bytewise(&config, &encode_all(&frames, &config));
+        assert_eq!(decoded, frames.into_iter().map(Ok).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn length_prefixed_split_at_every_boundary() {
+        let config = ProtocolConfig::new();
+        let frames = frames();
+        let bytes = encode_all(&frames, &config);
+        let expected: Vec<_> = frames.into_iter().map(Ok).collect();
+        for at in 0..=bytes.len() {
+            assert_eq!(decode_split(&config, &bytes, at), expected, "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn length_prefixed_oversize_skip() {
+        let config = ProtocolConfig::new().max_frame_size(16).oversize_policy(OversizePolicy::Skip);
+        let small = Frame::data(vec![7; 8]);
+        let mut bytes = raw_frame(17);
+        bytes.extend(encode_frame(&small, &config).unwrap());
+        let expected = vec![Err(ProtocolErrorKind::FrameTooLarge), Ok(small)];
+
+        assert_eq!(decode_bytewise(&config, &bytes), expected);
+        for at in 0..=bytes.len() {
+            assert_eq!(decode_split(&config, &bytes, at), expected, "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn length_prefixed_oversize_close() {
+        let config = ProtocolConfig::new().max_frame_size(16).oversize_policy(OversizePolicy::Close);
+        let small = Frame::data(vec![7; 8]);
+        let mut bytes = encode_frame(&small, &config).unwrap();
+        bytes.extend(raw_frame(17));
+        bytes.extend(encode_frame(&small, &config).unwrap());
+
+        let expected = vec![Ok(small), Err(ProtocolErrorKind::FrameTooLarge)];
+        assert_eq!(decode_bytewise(&config, &bytes), expected);
+        for at in 0..=bytes.len() {
+            assert_eq!(decode_split(&config, &bytes, at), expected, "split at {}", at);
+        }
+
+        // 受信を停止した後は、続くバイト列を渡してもエラーのまま
+        let mut decoder = FrameDecoder::with_config(config.clone());
+        decoder.extend(&raw_frame(17)[..4]);
+        assert!(decoder.next_frame().is_err());
+        assert!(decoder.is_closed());
+        decoder.extend(&encode_frame(&Frame::data(vec![1]), &config).unwrap());
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn length_prefixed_rejects_unknown_kind() {
+        let config = ProtocolConfig::new();
+        let mut decoder = FrameDecoder::with_config(config);
+        decoder.extend(&[1, 0, 0, 0, 0xFF]);
+        let error = decoder.next_frame().unwrap_err();
+        assert_eq!(ProtocolError::kind_of(&error), Some(ProtocolErrorKind::InvalidFrame));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn ndjson_bytewise() {
+        let config = ProtocolConfig::new().framing(Framing::Ndjson);
+        let bytes = b"{\"a\":1}\n\n  \r\n[2]\r\n\"x\"\n";
+        let expected = vec![
+            Ok(Frame::data(b"{\"a\":1}".to_vec())),
+            Ok(Frame::data(b"[2]".to_vec())),
+            Ok(Frame::data(b"\"x\"".to_vec())),
+        ];
+        assert_eq!(decode_bytewise(&config, bytes), expected);
+        for at in 0..=bytes.len() {
+            assert_eq!(decode_split(&config, bytes, at), expected, "split at {}", at);
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn ndjson_encode_limit_matches_decoder() {
+        let config = ProtocolConfig::new().framing(Framing::Ndjson).max_frame_size(8);
+        let bytes = encode_frame(&Frame::data(b"\"012345\"".to_vec()), &config).unwrap();
+        assert_eq!(bytes, b"\"012345\"\n");
+        assert_eq!(decode_bytewise(&config, &bytes), vec![Ok(Frame::data(b"\"012345\"".to_vec()))]);
+
+        let error = encode_frame(&Frame::data(b"\"0123456\"".to_vec()), &config).unwrap_err();
+        assert_eq!(ProtocolError::kind_of(&error), Some(ProtocolErrorKind::FrameTooLarge));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn ndjson_oversize_skip() {
+        let config = ProtocolConfig::new()
+            .framing(Framing::Ndjson)
+            .max_frame_size(8)
+            .oversize_policy(OversizePolicy::Skip);
+        let bytes = b"\"0123456789\"\n[1]\n";
+        let expected = vec![Err(ProtocolErrorKind::FrameTooLarge), Ok(Frame::data(b"[1]".to_vec()))];
+        assert_eq!(decode_bytewise(&config, bytes), expected);
+        for at in 0..=bytes.len() {
+            assert_eq!(decode_split(&config, bytes, at), expected, "split at {}", at);
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn ndjson_oversize_close() {
+        let config = ProtocolConfig::new()
+            .framing(Framing::Ndjson)
+            .max_frame_size(8)
+            .oversize_policy(OversizePolicy::Close);
+        let bytes = b"[1]\n\"0123456789\"\n[2]\n";
+        let expected = vec![Ok(Frame::data(b"[1]".to_vec())), Err(ProtocolErrorKind::FrameTooLarge)];
+        assert_eq!(decode_bytewise(&config, bytes), expected);
+        for at in 0..=bytes.len() {
+            assert_eq!(decode_split(&config, bytes, at), expected, "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn hello_roundtrip() {
+        let hello = Hello::local();
+        assert_eq!(Hello::decode(&hello.encode()).unwrap(), hello);
+    }
+
+    #[test]
+    fn hello_rejects_bad_magic() {
+        let mut bytes = Hello::local().encode();
+        bytes[..4].copy_from_slice(b"HTTP");
+        let error = Hello::decode(&bytes).unwrap_err();
+        assert_eq!(ProtocolError::kind_of(&error), Some(ProtocolErrorKind::BadMagic));
+    }
+
+    #[test]
+    fn negotiate_rejects_version_below_minimum() {
+        let peer = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::supported(),
+        };
+        let error = Hello::local().negotiate(&peer).unwrap_err();
+        assert_eq!(ProtocolError::kind_of(&error), Some(ProtocolErrorKind::VersionMismatch));
+        assert!(error.to_string().contains("version mismatch"), "{}", error);
+    }
+
+    #[test]
+    fn negotiate_uses_lower_version_and_common_capabilities() {
+        let local = Hello::local();
+        let peer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::from_bits(Capabilities::RPC.bits() | Capabilities::GOODBYE.bits() | 1 << 31),
+        };
+        let negotiated = local.negotiate(&peer).unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(
+            negotiated.capabilities,
+            Capabilities::from_bits(Capabilities::RPC.bits() | Capabilities::GOODBYE.bits())
+        );
+        assert!(!negotiated.capabilities.contains(Capabilities::PUBSUB));
+    }
+}
@@ -0,0 +1,160 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+
+/// [`Codec`]をクレートの外で実装できないようにするためのモジュール。
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// メッセージとフレーム本体のバイト列を相互に変換するコーデック。
+///
+/// 接続で使用するコーデックは、[`CodecKind`]で組み込みのものから選択します。
+/// このトレイトはシールされており、クレートの外で実装することはできません。
+/// 既定では[`Bincode`]が使用されます。cargoのfeatureを有効にすることで、
+/// Rust以外のプロセスと通信するためのコーデックを利用できます。
+///
+/// | feature   | コーデック        |
+/// |-----------|-------------------|
+/// | （既定）  | [`Bincode`]       |
+/// | `json`    | `Json`            |
+/// | `msgpack` | `MessagePack`     |
+/// | `cbor`    | `Cbor`            |
+pub trait Codec: sealed::Sealed {
+    /// メッセージをバイト列にエンコードします。
+    ///
+    /// # エラー
+    /// シリアライズに失敗した場合にエラーを返します。
+    fn encode<T: Serialize + ?Sized>(&self, message: &T) -> io::Result<Vec<u8>>;
+
+    /// バイト列をメッセージにデコードします。
+    ///
+    /// # エラー
+    /// デシリアライズに失敗した場合にエラーを返します。
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T>;
+}
+
+/// bincode v2（`config::standard()`）によるコーデック。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bincode;
+
+impl sealed::Sealed for Bincode {}
+
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(&self, message: &T) -> io::Result<Vec<u8>> {
+        bincode::serde::encode_to_vec(message, bincode::config::standard())
+            .map_err(io::Error::other)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        let (message, _) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(message)
+    }
+}
+
+/// JSONによるコーデック。デバッグや他言語との通信に使用します。
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl sealed::Sealed for Json {}
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(&self, message: &T) -> io::Result<Vec<u8>> {
+        serde_json::to_vec(message).map_err(io::Error::other)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// MessagePackによるコーデック。構造体はフィールド名付きのマップとしてエンコードされます。
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl sealed::Sealed for MessagePack {}
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize + ?Sized>(&self, message: &T) -> io::Result<Vec<u8>> {
+        rmp_serde::to_vec_named(message).map_err(io::Error::other)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        rmp_serde::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// CBORによるコーデック。
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl sealed::Sealed for Cbor {}
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize + ?Sized>(&self, message: &T) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(message, &mut bytes).map_err(io::Error::other)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        ciborium::from_reader(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// クライアントやサーバーごとに選択できるコーデックの種類。
+///
+/// [`ProtocolConfig::codec`](super::ProtocolConfig::codec)で指定します。
+/// 接続の両端で同じコーデックを使用する必要があります。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CodecKind {
+    /// [`Bincode`]を使用します。
+    #[default]
+    Bincode,
+    /// `Json`を使用します。
+    #[cfg(feature = "json")]
+    Json,
+    /// `MessagePack`を使用します。
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// `Cbor`を使用します。
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl sealed::Sealed for CodecKind {}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize + ?Sized>(&self, message: &T) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Bincode => Bincode.encode(message),
+            #[cfg(feature = "json")]
+            Self::Json => Json.encode(message),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack.encode(message),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor.encode(message),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        match self {
+            Self::Bincode => Bincode.decode(bytes),
+            #[cfg(feature = "json")]
+            Self::Json => Json.decode(bytes),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack.decode(bytes),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor.decode(bytes),
+        }
+    }
+}